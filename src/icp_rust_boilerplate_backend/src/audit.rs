@@ -0,0 +1,204 @@
+// Append-only audit event log kept in stable memory.
+//
+// Every mutating call is recorded with its caller, the operation, the document it
+// targeted and whether it succeeded, including calls rejected by validation or access
+// checks. Events are appended in time order and never rewritten.
+use crate::{authorize_admin, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Log, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Maximum number of events returned by a single audit query
+const MAX_AUDIT_PAGE_SIZE: u64 = 100;
+
+// Maximum number of events a single audit query looks at; filtered queries over a long
+// log return a partial page and a `next_index` to continue from
+const MAX_AUDIT_SCAN: u64 = 10_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum AuditOperation {
+    PurgeDocument,
+    PlaceLegalHold,
+    LiftLegalHold,
+    BeginUpload,
+    UploadChunk,
+    CommitUpload,
+    CreateDocument,
+    UpdateDocument,
+    RevertDocument,
+    SoftDeleteDocument,
+    RestoreDocument,
+    GrantPermission,
+    RevokePermission,
+    SetTrashRetention,
+    CheckoutDocument,
+    CheckinDocument,
+    BreakLock,
+    NotarizeDocument,
+    SignDocumentVersion,
+    CreateEnvelope,
+    SignEnvelope,
+    DeclineEnvelope,
+    VoidEnvelope,
+    SetLifecyclePolicy,
+    SubmitForReview,
+    ApproveDocument,
+    RejectDocument,
+    PublishDocument,
+    ArchiveBlocks,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum AuditOutcome {
+    Success,
+    Failure { msg: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditEvent {
+    // Position of the event in the log
+    index: u64,
+    // Principal that performed the operation; the canister itself for scheduled jobs
+    actor: Principal,
+    operation: AuditOperation,
+    document_id: Option<u64>,
+    timestamp: u64,
+    outcome: AuditOutcome,
+}
+
+// Criteria an event must meet to be returned; unset fields match every event
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AuditFilter {
+    actor: Option<Principal>,
+    document_id: Option<u64>,
+    // Inclusive bounds on the event timestamp
+    from_time: Option<u64>,
+    to_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AuditPage {
+    events: Vec<AuditEvent>,
+    next_index: Option<u64>,
+}
+
+// Storable trait for AuditEvent
+impl Storable for AuditEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static AUDIT_LOG: RefCell<Log<AuditEvent, Memory, Memory>> = RefCell::new(
+        Log::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+        .expect("Cannot create the audit log")
+    );
+}
+
+// Page through the audit events matching `filter`, oldest first, starting at
+// `from_index`
+#[ic_cdk::query]
+fn list_audit_events(filter: AuditFilter, from_index: u64, limit: u64) -> Result<AuditPage, Error> {
+    authorize_admin()?;
+
+    let limit = limit.clamp(1, MAX_AUDIT_PAGE_SIZE) as usize;
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let len = log.len();
+        let mut index = match filter.from_time {
+            Some(from_time) => from_index.max(first_at_or_after(&log, from_time)),
+            None => from_index,
+        };
+        let scan_end = index.saturating_add(MAX_AUDIT_SCAN).min(len);
+
+        let mut events = Vec::new();
+        while index < scan_end && events.len() < limit {
+            let Some(event) = log.get(index) else { break };
+            if filter.to_time.is_some_and(|to_time| event.timestamp > to_time) {
+                index = len;
+                break;
+            }
+            index += 1;
+            if matches(&event, &filter) {
+                events.push(event);
+            }
+        }
+        let next_index = if index < len { Some(index) } else { None };
+        Ok(AuditPage { events, next_index })
+    })
+}
+
+// Run `call` and record its outcome as performed by the caller
+pub(crate) fn audited<T>(
+    operation: AuditOperation,
+    document_id: Option<u64>,
+    call: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let result = call();
+    record_result(operation, document_id, &result);
+    result
+}
+
+// Record the outcome of an operation performed by the caller
+pub(crate) fn record_result<T>(
+    operation: AuditOperation,
+    document_id: Option<u64>,
+    result: &Result<T, Error>,
+) {
+    let outcome = match result {
+        Ok(_) => AuditOutcome::Success,
+        Err(error) => AuditOutcome::Failure { msg: error.to_string() },
+    };
+    record(caller(), operation, document_id, outcome);
+}
+
+// Append an event performed by `actor` at the current time
+pub(crate) fn record(
+    actor: Principal,
+    operation: AuditOperation,
+    document_id: Option<u64>,
+    outcome: AuditOutcome,
+) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let event = AuditEvent {
+            index: log.len(),
+            actor,
+            operation,
+            document_id,
+            timestamp: time(),
+            outcome,
+        };
+        log.append(&event).expect("cannot append to the audit log");
+    });
+}
+
+fn matches(event: &AuditEvent, filter: &AuditFilter) -> bool {
+    filter.actor.is_none_or(|actor| event.actor == actor)
+        && filter.document_id.is_none_or(|id| event.document_id == Some(id))
+        && filter.from_time.is_none_or(|from_time| event.timestamp >= from_time)
+}
+
+// Index of the first event at or after `timestamp`; events are appended in time order
+fn first_at_or_after(log: &Log<AuditEvent, Memory, Memory>, timestamp: u64) -> u64 {
+    let (mut low, mut high) = (0, log.len());
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if log.get(middle).is_some_and(|event| event.timestamp < timestamp) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
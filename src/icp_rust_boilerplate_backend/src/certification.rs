@@ -0,0 +1,175 @@
+// Certified variables for document reads.
+//
+// The canister keeps a Merkle tree mapping each stored document id (big-endian) to the
+// hash of the document and publishes its root with `set_certified_data` after every
+// mutation. Query responses carry the subnet certificate together with a witness for
+// the requested document, so a client can check the response against the IC root key
+// instead of trusting the replica that answered it.
+//
+// Once the ICRC-3 block log has a block, the root also holds the `last_block_hash` and
+// `last_block_index` labels required by ICRC-3 next to the document subtree.
+use crate::integrity::{write_field, write_optional};
+use crate::{icrc3, schema, Document, STORAGE};
+use candid::Nat;
+use ic_certified_map::{
+    fork, fork_hash, labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree,
+};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Labels at the root of the certified tree, in the sorted order the tree requires
+const DOCUMENTS_LABEL: &[u8] = b"documents";
+const LAST_BLOCK_HASH_LABEL: &[u8] = b"last_block_hash";
+const LAST_BLOCK_INDEX_LABEL: &[u8] = b"last_block_index";
+
+thread_local! {
+    // Rebuilt from STORAGE on install and upgrade, since it lives on the heap
+    static TREE: RefCell<RbTree<[u8; 8], Hash>> = const { RefCell::new(RbTree::new()) };
+
+    // LEB128-encoded index and hash of the latest ICRC-3 block
+    static TIP: RefCell<Option<(Vec<u8>, Hash)>> = const { RefCell::new(None) };
+}
+
+// A document with the proof needed to verify it against the subnet's public key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CertifiedDocument {
+    document: Document,
+    // Subnet certificate over the canister's certified data; only present in query calls
+    certificate: Option<ByteBuf>,
+    // CBOR-encoded hash tree revealing the document's hash under /documents/<id>
+    witness: ByteBuf,
+}
+
+// Recompute the tree from every stored document and publish its root
+pub(crate) fn rebuild_tree() {
+    TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        *tree = RbTree::new();
+        STORAGE.with(|service| {
+            for (id, document) in service.borrow().iter().filter_map(schema::decoded) {
+                tree.insert(id.to_be_bytes(), document_hash(&document));
+            }
+        });
+    });
+    let last_block = icrc3::last_block().map(|(index, hash)| (leb128(index), hash));
+    TIP.with(|tip| *tip.borrow_mut() = last_block);
+    update_certified_data();
+}
+
+// Record the current state of a stored document
+pub(crate) fn certify_document(document: &Document) {
+    TREE.with(|tree| {
+        tree.borrow_mut()
+            .insert(document.id.to_be_bytes(), document_hash(document))
+    });
+    update_certified_data();
+}
+
+// Drop a document that has been removed from storage
+pub(crate) fn uncertify_document(id: u64) {
+    TREE.with(|tree| tree.borrow_mut().delete(&id.to_be_bytes()));
+    update_certified_data();
+}
+
+// Record the latest block of the ICRC-3 log
+pub(crate) fn certify_tip(index: u64, hash: Hash) {
+    TIP.with(|tip| *tip.borrow_mut() = Some((leb128(index), hash)));
+    update_certified_data();
+}
+
+// Attach the data certificate and a witness for the document to the response
+pub(crate) fn certified_document(document: Document) -> CertifiedDocument {
+    let witness = TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness = labeled(DOCUMENTS_LABEL, tree.witness(&document.id.to_be_bytes()));
+        TIP.with(|tip| match &*tip.borrow() {
+            Some((index, hash)) => {
+                encode(&fork(witness, HashTree::Pruned(tip_tree(index, hash).reconstruct())))
+            }
+            None => encode(&witness),
+        })
+    });
+
+    CertifiedDocument {
+        document,
+        certificate: ic_cdk::api::data_certificate().map(ByteBuf::from),
+        witness: ByteBuf::from(witness),
+    }
+}
+
+// CBOR-encoded witness for the ICRC-3 tip labels, once the log has a block
+pub(crate) fn tip_witness() -> Option<Vec<u8>> {
+    TIP.with(|tip| {
+        let tip = tip.borrow();
+        let (index, hash) = tip.as_ref()?;
+        let documents = HashTree::Pruned(documents_hash());
+        Some(encode(&fork(documents, tip_tree(index, hash))))
+    })
+}
+
+fn update_certified_data() {
+    let documents = documents_hash();
+    let root_hash = TIP.with(|tip| match &*tip.borrow() {
+        Some((index, hash)) => fork_hash(&documents, &tip_tree(index, hash).reconstruct()),
+        None => documents,
+    });
+    ic_cdk::api::set_certified_data(&root_hash);
+}
+
+fn documents_hash() -> Hash {
+    TREE.with(|tree| labeled_hash(DOCUMENTS_LABEL, &tree.borrow().root_hash()))
+}
+
+fn tip_tree<'a>(index: &'a [u8], hash: &'a Hash) -> HashTree<'a> {
+    fork(
+        labeled(LAST_BLOCK_HASH_LABEL, HashTree::Leaf(Cow::Borrowed(hash))),
+        labeled(LAST_BLOCK_INDEX_LABEL, HashTree::Leaf(Cow::Borrowed(index))),
+    )
+}
+
+fn leb128(index: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    Nat::from(index).encode(&mut bytes).expect("cannot encode the block index");
+    bytes
+}
+
+fn encode(tree: &HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::Serializer::new(Vec::new());
+    serializer.self_describe().expect("cannot encode the witness");
+    tree.serialize(&mut serializer).expect("cannot encode the witness");
+    serializer.into_inner()
+}
+
+// SHA-256 over the length-prefixed fields of the document, in declaration order;
+// optional fields are preceded by a presence byte. Clients recompute this hash from
+// the returned document and compare it with the leaf in the witness.
+pub(crate) fn document_hash(document: &Document) -> Hash {
+    let mut hasher = Sha256::new();
+    write_field(&mut hasher, &document.id.to_be_bytes());
+    write_field(&mut hasher, document.owner.as_slice());
+    write_field(&mut hasher, document.title.as_bytes());
+    write_field(&mut hasher, document.description.as_bytes());
+    write_field(&mut hasher, document.file_url.as_bytes());
+    let content_id = document.content_id.map(u64::to_be_bytes);
+    write_optional(&mut hasher, content_id.as_ref().map(|id| &id[..]));
+    write_field(&mut hasher, &document.version.to_be_bytes());
+    write_field(&mut hasher, &document.created_at.to_be_bytes());
+    let updated_at = document.updated_at.map(u64::to_be_bytes);
+    write_optional(&mut hasher, updated_at.as_ref().map(|at| &at[..]));
+    write_optional(&mut hasher, document.updated_by.as_ref().map(|by| by.as_slice()));
+    write_field(&mut hasher, &[document.is_deleted as u8]);
+    match &document.deletion {
+        Some(deletion) => {
+            hasher.update([1]);
+            write_field(&mut hasher, deletion.deleted_by.as_slice());
+            write_field(&mut hasher, &deletion.deleted_at.to_be_bytes());
+            write_optional(&mut hasher, deletion.reason.as_ref().map(String::as_bytes));
+        }
+        None => hasher.update([0]),
+    }
+    write_field(&mut hasher, &document.history_head);
+    hasher.finalize().into()
+}
@@ -0,0 +1,125 @@
+// Change feed for incremental sync.
+//
+// Every mutation of a document appends a change with the next global sequence number,
+// starting at 1. Clients remember the last sequence they processed and call
+// `changes_since` with it to fetch only what changed afterwards.
+use crate::{has_role, load_document, Memory, Role, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Log, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Maximum number of changes returned by a single query
+const MAX_CHANGES_PAGE_SIZE: u64 = 500;
+
+// Maximum number of changes a single query looks at; changes the caller cannot see
+// still count, so a page may come back short with a `next_sequence` to continue from
+const MAX_CHANGES_SCAN: u64 = 10_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Purged,
+    AccessGranted,
+    AccessRevoked,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Change {
+    sequence: u64,
+    document_id: u64,
+    kind: ChangeKind,
+    // Version of the document after the change
+    version: u64,
+    // Principal whose access changed, for AccessGranted and AccessRevoked
+    principal: Option<Principal>,
+    timestamp: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ChangePage {
+    changes: Vec<Change>,
+    // Sequence to pass to the next call; the latest sequence once the feed is drained
+    next_sequence: u64,
+    has_more: bool,
+}
+
+// Storable trait for Change
+impl Storable for Change {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    // Change with sequence n is stored at index n - 1
+    static CHANGES: RefCell<Log<Change, Memory, Memory>> = RefCell::new(
+        Log::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        )
+        .expect("Cannot create the change feed")
+    );
+}
+
+// Changes after `sequence`, oldest first, to documents the caller can read. Purges
+// are returned for every document since there is nothing left to check access on,
+// and access changes are also returned to the principal they concern.
+#[ic_cdk::query]
+fn changes_since(sequence: u64, limit: u64) -> ChangePage {
+    let principal = caller();
+    let limit = limit.clamp(1, MAX_CHANGES_PAGE_SIZE) as usize;
+    CHANGES.with(|log| {
+        let log = log.borrow();
+        let len = log.len();
+        let mut index = sequence.min(len);
+        let scan_end = index.saturating_add(MAX_CHANGES_SCAN).min(len);
+
+        let mut changes = Vec::new();
+        while index < scan_end && changes.len() < limit {
+            let Some(change) = log.get(index) else { break };
+            index += 1;
+            if is_visible(&change, &principal) {
+                changes.push(change);
+            }
+        }
+        ChangePage { changes, next_sequence: index, has_more: index < len }
+    })
+}
+
+// Append a change to the document made at the current time
+pub(crate) fn record(
+    document_id: u64,
+    kind: ChangeKind,
+    version: u64,
+    principal: Option<Principal>,
+) {
+    CHANGES.with(|log| {
+        let log = log.borrow();
+        let change = Change {
+            sequence: log.len() + 1,
+            document_id,
+            kind,
+            version,
+            principal,
+            timestamp: time(),
+        };
+        log.append(&change).expect("cannot append to the change feed");
+    });
+}
+
+fn is_visible(change: &Change, principal: &Principal) -> bool {
+    if change.kind == ChangeKind::Purged || change.principal.as_ref() == Some(principal) {
+        return true;
+    }
+    load_document(change.document_id)
+        .is_ok_and(|document| has_role(&document, principal, Role::Reader))
+}
@@ -0,0 +1,512 @@
+// On-canister file content, stored in fixed-size chunks in stable memory.
+//
+// An upload is started with `begin_upload`, which reserves a content id and fixes the
+// total size. Chunks can then be sent in any order and re-sent after a failure;
+// `get_upload_status` reports which ones are still missing. Once every chunk is
+// present, `commit_upload` seals the content so it can be linked to a document.
+//
+// Content nobody attaches is not kept forever: an upload that is not committed within
+// a day, or committed content that is not attached to a document within a week, is
+// deleted by a timer. Until it is attached, content counts against a per-principal
+// quota of bytes and a quota for the whole canister.
+use crate::audit::{self, AuditOperation};
+use crate::{
+    authenticate, authorize, load_document, principal_key, Error, Memory, PrincipalKey, Role,
+    MEMORY_MANAGER, NANOS_PER_SECOND,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Size of every chunk except the last one of a content
+pub(crate) const CHUNK_SIZE: u64 = 64 * 1024;
+
+// Largest content accepted by `begin_upload`
+pub(crate) const MAX_CONTENT_SIZE: u64 = 256 * 1024 * 1024;
+
+// Bytes a principal can hold in uploads and in committed content not yet attached
+pub(crate) const MAX_UNATTACHED_BYTES_PER_PRINCIPAL: u64 = 2 * MAX_CONTENT_SIZE;
+
+// Bytes all principals together can hold in uploads and unattached content
+pub(crate) const MAX_UNATTACHED_BYTES_TOTAL: u64 = 32 * MAX_CONTENT_SIZE;
+
+// Time an upload has to be committed
+const UPLOAD_EXPIRY_SECONDS: u64 = 24 * 60 * 60;
+
+// Time committed content has to be attached to a document
+const UNATTACHED_EXPIRY_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+// How often expired content is deleted, and how many contents each run deletes at most
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const MAX_EXPIRED_PER_RUN: usize = 10;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ContentStatus {
+    Uploading,
+    Committed,
+}
+
+// Bookkeeping for a piece of content, from the start of its upload onwards
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ContentInfo {
+    pub(crate) id: u64,
+    pub(crate) uploader: Principal,
+    pub(crate) size: u64,
+    pub(crate) chunk_size: u64,
+    pub(crate) chunk_count: u64,
+    pub(crate) received_chunks: u64,
+    pub(crate) status: ContentStatus,
+    pub(crate) created_at: u64,
+    pub(crate) committed_at: Option<u64>,
+    // Document the content has been attached to, if any
+    pub(crate) document_id: Option<u64>,
+}
+
+// Progress of an upload, used by clients to resume after a failure
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct UploadStatus {
+    content: ContentInfo,
+    missing_chunks: Vec<u64>,
+    // When the content is deleted unless it is committed or attached first
+    expires_at: Option<u64>,
+}
+
+// Raw bytes of a single chunk
+#[derive(Clone, Default)]
+struct Chunk(Vec<u8>);
+
+// Storable trait for ContentInfo
+impl Storable for ContentInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for ContentInfo
+impl BoundedStorable for ContentInfo {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable trait for Chunk
+impl Storable for Chunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Chunk(bytes.into_owned())
+    }
+}
+
+// BoundedStorable trait for Chunk
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CONTENT_ID_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))), 0)
+            .expect("Cannot create a content counter")
+    );
+
+    static CONTENTS: RefCell<StableBTreeMap<u64, ContentInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    // Chunk bytes keyed by (content id, chunk index)
+    static CHUNKS: RefCell<StableBTreeMap<(u64, u64), Chunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    // Content that is not attached to a document, keyed by (expiry time, content id)
+    static EXPIRIES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    // Bytes of unattached content held by each uploader
+    static UNATTACHED_BYTES: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    // Sum of UNATTACHED_BYTES
+    static TOTAL_UNATTACHED_BYTES: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))), 0)
+            .expect("Cannot create the unattached bytes total")
+    );
+}
+
+pub(crate) fn start_timers() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, delete_expired_content);
+}
+
+// Reserve a content id for an upload of `size` bytes
+#[ic_cdk::update]
+fn begin_upload(size: u64) -> Result<ContentInfo, Error> {
+    audit::audited(AuditOperation::BeginUpload, None, || {
+        authenticate()?;
+        if size == 0 || size > MAX_CONTENT_SIZE {
+            return Err(Error::InvalidArgument {
+                msg: format!("Content size must be between 1 and {} bytes", MAX_CONTENT_SIZE),
+            });
+        }
+        let held = unattached_bytes(&caller());
+        if held.saturating_add(size) > MAX_UNATTACHED_BYTES_PER_PRINCIPAL {
+            return Err(Error::QuotaExceeded {
+                msg: format!(
+                    "Uploads and unattached content are limited to {} bytes per principal, \
+                     {} are in use",
+                    MAX_UNATTACHED_BYTES_PER_PRINCIPAL, held
+                ),
+            });
+        }
+        let total = total_unattached_bytes();
+        if total.saturating_add(size) > MAX_UNATTACHED_BYTES_TOTAL {
+            return Err(Error::QuotaExceeded {
+                msg: format!(
+                    "Uploads and unattached content are limited to {} bytes for the canister, \
+                     {} are in use",
+                    MAX_UNATTACHED_BYTES_TOTAL, total
+                ),
+            });
+        }
+
+        let id = CONTENT_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment content id counter");
+
+        let content = ContentInfo {
+            id,
+            uploader: caller(),
+            size,
+            chunk_size: CHUNK_SIZE,
+            chunk_count: size.div_ceil(CHUNK_SIZE),
+            received_chunks: 0,
+            status: ContentStatus::Uploading,
+            created_at: time(),
+            committed_at: None,
+            document_id: None,
+        };
+        do_insert_content(&content);
+        Ok(content)
+    })
+}
+
+// Store one chunk of an upload; sending the same chunk again overwrites it
+#[ic_cdk::update]
+fn upload_chunk(content_id: u64, index: u64, bytes: ByteBuf) -> Result<ContentInfo, Error> {
+    audit::audited(AuditOperation::UploadChunk, None, || {
+        authenticate()?;
+        let mut content = load_upload(content_id)?;
+
+        if index >= content.chunk_count {
+            return Err(Error::InvalidArgument {
+                msg: format!("Chunk index {} is out of range 0..{}", index, content.chunk_count),
+            });
+        }
+        let expected = expected_chunk_len(&content, index);
+        if bytes.len() as u64 != expected {
+            return Err(Error::InvalidArgument {
+                msg: format!(
+                    "Chunk {} must be exactly {} bytes, got {}",
+                    index,
+                    expected,
+                    bytes.len()
+                ),
+            });
+        }
+
+        let previous = CHUNKS.with(|chunks| {
+            chunks
+                .borrow_mut()
+                .insert((content_id, index), Chunk(bytes.into_vec()))
+        });
+        if previous.is_none() {
+            content.received_chunks += 1;
+            do_insert_content(&content);
+        }
+        Ok(content)
+    })
+}
+
+// Seal an upload once every chunk has been received
+#[ic_cdk::update]
+fn commit_upload(content_id: u64) -> Result<ContentInfo, Error> {
+    audit::audited(AuditOperation::CommitUpload, None, || {
+        authenticate()?;
+        let mut content = load_upload(content_id)?;
+
+        if content.received_chunks != content.chunk_count {
+            return Err(Error::InvalidArgument {
+                msg: format!(
+                    "Upload {} is missing {} of {} chunks",
+                    content_id,
+                    content.chunk_count - content.received_chunks,
+                    content.chunk_count
+                ),
+            });
+        }
+
+        content.status = ContentStatus::Committed;
+        content.committed_at = Some(time());
+        do_insert_content(&content);
+        Ok(content)
+    })
+}
+
+// Report the progress of an upload, including the chunks still missing
+#[ic_cdk::query]
+fn get_upload_status(content_id: u64) -> Result<UploadStatus, Error> {
+    let content = load_content(content_id)?;
+    if content.uploader != caller() {
+        return Err(unauthorized(content_id));
+    }
+
+    let missing_chunks = CHUNKS.with(|chunks| {
+        let chunks = chunks.borrow();
+        (0..content.chunk_count)
+            .filter(|index| !chunks.contains_key(&(content_id, *index)))
+            .collect()
+    });
+    let expires_at = expires_at(&content);
+    Ok(UploadStatus { content, missing_chunks, expires_at })
+}
+
+// Retrieve the description of a committed content
+#[ic_cdk::query]
+fn get_content_info(content_id: u64) -> Result<ContentInfo, Error> {
+    let content = load_content(content_id)?;
+    authorize_download(&content)?;
+    Ok(content)
+}
+
+// Download one chunk of a committed content
+#[ic_cdk::query]
+fn get_chunk(content_id: u64, index: u64) -> Result<ByteBuf, Error> {
+    let content = load_content(content_id)?;
+    authorize_download(&content)?;
+    if content.status != ContentStatus::Committed {
+        return Err(Error::InvalidArgument {
+            msg: format!("Content {} has not been committed", content_id),
+        });
+    }
+
+    CHUNKS
+        .with(|chunks| chunks.borrow().get(&(content_id, index)))
+        .map(|chunk| ByteBuf::from(chunk.0))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Chunk {} of content {} not found", index, content_id),
+        })
+}
+
+// Check that the caller may attach the content to the given document and record the link.
+// Content is attached to at most one document, but may be reused by its later versions.
+pub(crate) fn attach_content(content_id: u64, document_id: u64) -> Result<(), Error> {
+    let mut content = check_attachable(content_id, Some(document_id))?;
+    if content.document_id.is_none() {
+        content.document_id = Some(document_id);
+        do_insert_content(&content);
+    }
+    Ok(())
+}
+
+// Validate a content reference before a new document id has been assigned
+pub(crate) fn check_attachable(
+    content_id: u64,
+    document_id: Option<u64>,
+) -> Result<ContentInfo, Error> {
+    let content = load_content(content_id)?;
+    if content.status != ContentStatus::Committed {
+        return Err(Error::InvalidArgument {
+            msg: format!("Content {} has not been committed", content_id),
+        });
+    }
+
+    match content.document_id {
+        None if content.uploader == caller() => Ok(content),
+        None => Err(unauthorized(content_id)),
+        Some(linked) if Some(linked) == document_id => Ok(content),
+        Some(linked) => Err(Error::InvalidArgument {
+            msg: format!("Content {} is already attached to document {}", content_id, linked),
+        }),
+    }
+}
+
+// Remove a content and all of its chunks
+pub(crate) fn delete_content(content_id: u64) {
+    if let Some(content) = CONTENTS.with(|contents| contents.borrow_mut().remove(&content_id)) {
+        untrack(&content);
+        CHUNKS.with(|chunks| {
+            let mut chunks = chunks.borrow_mut();
+            for index in 0..content.chunk_count {
+                chunks.remove(&(content_id, index));
+            }
+        });
+    }
+}
+
+fn load_content(content_id: u64) -> Result<ContentInfo, Error> {
+    CONTENTS
+        .with(|contents| contents.borrow().get(&content_id))
+        .ok_or_else(|| Error::NotFound { msg: format!("Content with id {} not found", content_id) })
+}
+
+// Load an upload that the caller started and that is still accepting chunks
+fn load_upload(content_id: u64) -> Result<ContentInfo, Error> {
+    let content = load_content(content_id)?;
+    if content.uploader != caller() {
+        return Err(unauthorized(content_id));
+    }
+    if content.status != ContentStatus::Uploading {
+        return Err(Error::InvalidArgument {
+            msg: format!("Upload {} has already been committed", content_id),
+        });
+    }
+    Ok(content)
+}
+
+// Schedule the expiry of the stored contents; run by the schema migration that
+// introduced it
+pub(crate) fn rebuild_expiries() {
+    let contents: Vec<ContentInfo> = CONTENTS
+        .with(|contents| contents.borrow().iter().map(|(_, content)| content).collect());
+    for content in &contents {
+        track(content);
+    }
+}
+
+// Sum the unattached bytes of every principal; run by the schema migration that
+// introduced the canister-wide quota
+pub(crate) fn rebuild_total_unattached_bytes() {
+    let total = UNATTACHED_BYTES.with(|usage| {
+        usage.borrow().iter().fold(0u64, |total, (_, bytes)| total.saturating_add(bytes))
+    });
+    set_total_unattached_bytes(total);
+}
+
+fn do_insert_content(content: &ContentInfo) {
+    let previous =
+        CONTENTS.with(|contents| contents.borrow_mut().insert(content.id, content.clone()));
+    if let Some(previous) = previous {
+        untrack(&previous);
+    }
+    track(content);
+}
+
+// When the content is deleted if it stays as it is; attached content does not expire
+fn expires_at(content: &ContentInfo) -> Option<u64> {
+    match (content.status, content.committed_at, content.document_id) {
+        (_, _, Some(_)) => None,
+        (ContentStatus::Committed, Some(committed_at), None) => {
+            Some(committed_at.saturating_add(UNATTACHED_EXPIRY_SECONDS * NANOS_PER_SECOND))
+        }
+        _ => Some(content.created_at.saturating_add(UPLOAD_EXPIRY_SECONDS * NANOS_PER_SECOND)),
+    }
+}
+
+// Schedule the expiry of unattached content and count it against its uploader's quota
+fn track(content: &ContentInfo) {
+    if let Some(deadline) = expires_at(content) {
+        EXPIRIES.with(|expiries| expiries.borrow_mut().insert((deadline, content.id), ()));
+        update_unattached_bytes(&content.uploader, |bytes| bytes.saturating_add(content.size));
+    }
+}
+
+// Undo `track` for the content as it was stored
+fn untrack(content: &ContentInfo) {
+    if let Some(deadline) = expires_at(content) {
+        EXPIRIES.with(|expiries| expiries.borrow_mut().remove(&(deadline, content.id)));
+        update_unattached_bytes(&content.uploader, |bytes| bytes.saturating_sub(content.size));
+    }
+}
+
+fn unattached_bytes(principal: &Principal) -> u64 {
+    UNATTACHED_BYTES.with(|usage| usage.borrow().get(&principal_key(principal))).unwrap_or(0)
+}
+
+// Also keeps TOTAL_UNATTACHED_BYTES in step
+fn update_unattached_bytes(principal: &Principal, update: impl FnOnce(u64) -> u64) {
+    let key = principal_key(principal);
+    let (before, after) = UNATTACHED_BYTES.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let before = usage.get(&key).unwrap_or(0);
+        let after = update(before);
+        match after {
+            0 => usage.remove(&key),
+            bytes => usage.insert(key, bytes),
+        };
+        (before, after)
+    });
+    let total = total_unattached_bytes();
+    set_total_unattached_bytes(total.saturating_sub(before).saturating_add(after));
+}
+
+fn total_unattached_bytes() -> u64 {
+    TOTAL_UNATTACHED_BYTES.with(|total| *total.borrow().get())
+}
+
+fn set_total_unattached_bytes(bytes: u64) {
+    TOTAL_UNATTACHED_BYTES
+        .with(|total| total.borrow_mut().set(bytes))
+        .expect("cannot update the unattached bytes total");
+}
+
+fn delete_expired_content() {
+    let now = time();
+    let expired: Vec<u64> = EXPIRIES.with(|expiries| {
+        expiries
+            .borrow()
+            .range((0, 0)..=(now, u64::MAX))
+            .take(MAX_EXPIRED_PER_RUN)
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    for content_id in expired {
+        delete_content(content_id);
+    }
+}
+
+fn expected_chunk_len(content: &ContentInfo, index: u64) -> u64 {
+    if index + 1 == content.chunk_count {
+        content.size - index * CHUNK_SIZE
+    } else {
+        CHUNK_SIZE
+    }
+}
+
+// The uploader can always read its content; everyone else needs read access to the
+// document it is attached to
+fn authorize_download(content: &ContentInfo) -> Result<(), Error> {
+    if content.uploader == caller() {
+        return Ok(());
+    }
+    match content.document_id {
+        Some(document_id) => {
+            let document = load_document(document_id)?;
+            authorize(&document, Role::Reader)
+        }
+        None => Err(unauthorized(content.id)),
+    }
+}
+
+fn unauthorized(content_id: u64) -> Error {
+    Error::Unauthorized {
+        msg: format!("Principal {} is not allowed to access content {}", caller(), content_id),
+    }
+}
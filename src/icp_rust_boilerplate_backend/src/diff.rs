@@ -0,0 +1,138 @@
+// Field-level and line-level comparison of two document versions.
+use crate::DocumentVersion;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum DiffField {
+    Title,
+    Description,
+    FileUrl,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum LineChange {
+    Unchanged,
+    Added,
+    Removed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LineDiff {
+    change: LineChange,
+    text: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldDiff {
+    field: DiffField,
+    changed: bool,
+    old_value: String,
+    new_value: String,
+    // Line-by-line edit script from the old value to the new one; empty when unchanged
+    lines: Vec<LineDiff>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct VersionDiff {
+    document_id: u64,
+    from_version: u64,
+    to_version: u64,
+    content_changed: bool,
+    fields: Vec<FieldDiff>,
+}
+
+pub(crate) fn diff_versions(
+    document_id: u64,
+    from: &DocumentVersion,
+    to: &DocumentVersion,
+) -> VersionDiff {
+    VersionDiff {
+        document_id,
+        from_version: from.version,
+        to_version: to.version,
+        content_changed: from.content_id != to.content_id,
+        fields: vec![
+            diff_field(DiffField::Title, &from.title, &to.title),
+            diff_field(DiffField::Description, &from.description, &to.description),
+            diff_field(DiffField::FileUrl, &from.file_url, &to.file_url),
+        ],
+    }
+}
+
+fn diff_field(field: DiffField, old_value: &str, new_value: &str) -> FieldDiff {
+    let changed = old_value != new_value;
+    FieldDiff {
+        field,
+        changed,
+        old_value: old_value.to_string(),
+        new_value: new_value.to_string(),
+        lines: if changed { diff_lines(old_value, new_value) } else { Vec::new() },
+    }
+}
+
+// Edit script built from the longest common subsequence of the two texts' lines
+fn diff_lines(old_value: &str, new_value: &str) -> Vec<LineDiff> {
+    let old: Vec<&str> = old_value.lines().collect();
+    let new: Vec<&str> = new_value.lines().collect();
+
+    // lcs[i][j] is the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let line = |change, text: &str| LineDiff { change, text: text.to_string() };
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(line(LineChange::Unchanged, old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(line(LineChange::Removed, old[i]));
+            i += 1;
+        } else {
+            lines.push(line(LineChange::Added, new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|text| line(LineChange::Removed, text)));
+    lines.extend(new[j..].iter().map(|text| line(LineChange::Added, text)));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The edit script as lines prefixed with ' ', '+' or '-'
+    fn script(old_value: &str, new_value: &str) -> Vec<String> {
+        diff_lines(old_value, new_value)
+            .into_iter()
+            .map(|line| {
+                let prefix = match line.change {
+                    LineChange::Unchanged => ' ',
+                    LineChange::Added => '+',
+                    LineChange::Removed => '-',
+                };
+                format!("{}{}", prefix, line.text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diffs_lines() {
+        assert_eq!(script("a\nb", "a\nb"), [" a", " b"]);
+        assert_eq!(script("", "a\nb"), ["+a", "+b"]);
+        assert_eq!(script("a\nb", ""), ["-a", "-b"]);
+        assert_eq!(script("a\nb\nc", "a\nx\nc"), [" a", "-b", "+x", " c"]);
+        assert_eq!(script("a\nc", "a\nb\nc\nd"), [" a", "+b", " c", "+d"]);
+        assert_eq!(script("a\nb\nc\nd", "b\nd"), ["-a", " b", "-c", " d"]);
+    }
+}
@@ -0,0 +1,454 @@
+// Ordered multi-party signing envelopes.
+//
+// The owner of a document sends a version of it for signature to a list of signers.
+// Signers act one at a time in the given order: the current signer either signs,
+// which hands the envelope to the next one, or declines with a reason, which ends
+// it. An envelope completes once everybody has signed, expires when its deadline
+// passes and can be voided by its sender while it is pending. Writing a new version
+// of the document voids its pending envelopes, since they no longer cover the
+// current content.
+use crate::audit::{self, AuditOperation};
+use crate::{
+    authenticate, authorize, has_role, load_document, principal_key, Error, Memory, PrincipalKey,
+    Role, MAX_REASON_LEN, MEMORY_MANAGER, NANOS_PER_SECOND,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Maximum number of signers on a single envelope
+const MAX_SIGNERS: usize = 20;
+
+// Time signers have when the sender does not set a deadline
+const DEFAULT_EXPIRY_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+// Longest deadline a sender can set
+const MAX_EXPIRY_SECONDS: u64 = 365 * 24 * 60 * 60;
+
+// How often pending envelopes past their deadline are marked as expired
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum EnvelopeStatus {
+    Pending,
+    Completed,
+    Declined,
+    Expired,
+    Voided,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum SignerStatus {
+    Waiting,
+    Signed,
+    Declined,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct EnvelopeSigner {
+    principal: Principal,
+    status: SignerStatus,
+    acted_at: Option<u64>,
+    // Why the signer declined
+    reason: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    id: u64,
+    document_id: u64,
+    // Version sent for signature and its hash from the history chain
+    version: u64,
+    version_hash: ByteBuf,
+    sender: Principal,
+    created_at: u64,
+    expires_at: u64,
+    // Signers in signing order
+    signers: Vec<EnvelopeSigner>,
+    status: EnvelopeStatus,
+    // When the envelope left the Pending state
+    closed_at: Option<u64>,
+    void_reason: Option<String>,
+}
+
+// Storable trait for Envelope
+impl Storable for Envelope {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for Envelope
+impl BoundedStorable for Envelope {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ENVELOPE_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))), 0)
+            .expect("Cannot create the envelope counter")
+    );
+
+    static ENVELOPES: RefCell<StableBTreeMap<u64, Envelope, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    // Pending envelopes keyed by (current signer, envelope id)
+    static INBOX: RefCell<StableBTreeMap<(PrincipalKey, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // Envelopes of each document keyed by (document id, envelope id)
+    static DOCUMENT_ENVELOPES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    // Pending envelopes keyed by (deadline, envelope id)
+    static DEADLINES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+}
+
+pub(crate) fn start_timers() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, expire_envelopes);
+}
+
+// Send the current version of a document for signature by `signers`, in that order
+#[ic_cdk::update]
+fn create_envelope(
+    document_id: u64,
+    signers: Vec<Principal>,
+    expires_in_seconds: Option<u64>,
+) -> Result<Envelope, Error> {
+    audit::audited(AuditOperation::CreateEnvelope, Some(document_id), || {
+        authenticate()?;
+        let document = load_document(document_id)?;
+        if document.is_deleted {
+            return Err(Error::DocumentDeleted);
+        }
+        authorize(&document, Role::Owner)?;
+
+        if signers.is_empty() || signers.len() > MAX_SIGNERS {
+            return Err(Error::InvalidArgument {
+                msg: format!("An envelope needs between 1 and {} signers", MAX_SIGNERS),
+            });
+        }
+        for (position, signer) in signers.iter().enumerate() {
+            if signers[..position].contains(signer) {
+                return Err(Error::InvalidArgument { msg: format!("{} is listed twice", signer) });
+            }
+            if !has_role(&document, signer, Role::Reader) {
+                return Err(Error::InvalidArgument {
+                    msg: format!("{} cannot read document {}", signer, document_id),
+                });
+            }
+        }
+        let expires_in_seconds = expires_in_seconds.unwrap_or(DEFAULT_EXPIRY_SECONDS);
+        if expires_in_seconds == 0 || expires_in_seconds > MAX_EXPIRY_SECONDS {
+            return Err(Error::InvalidArgument {
+                msg: format!("Expiry must be between 1 and {} seconds", MAX_EXPIRY_SECONDS),
+            });
+        }
+
+        let id = ENVELOPE_COUNTER.with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1).map(|_| current_value)
+        })
+        .expect("cannot increment the envelope counter");
+
+        let envelope = Envelope {
+            id,
+            document_id,
+            version: document.version,
+            version_hash: document.history_head,
+            sender: caller(),
+            created_at: time(),
+            expires_at: time().saturating_add(expires_in_seconds.saturating_mul(NANOS_PER_SECOND)),
+            signers: signers
+                .into_iter()
+                .map(|principal| EnvelopeSigner {
+                    principal,
+                    status: SignerStatus::Waiting,
+                    acted_at: None,
+                    reason: None,
+                })
+                .collect(),
+            status: EnvelopeStatus::Pending,
+            closed_at: None,
+            void_reason: None,
+        };
+        save(&envelope);
+        Ok(envelope)
+    })
+}
+
+// Sign an envelope whose turn is the caller's
+#[ic_cdk::update]
+fn sign_envelope(id: u64) -> Result<Envelope, Error> {
+    audited_envelope(AuditOperation::SignEnvelope, id, || {
+        authenticate()?;
+        let mut envelope = pending_envelope(id)?;
+        let position = current_position(&envelope, &caller())?;
+
+        let document = load_document(envelope.document_id)?;
+        if document.is_deleted {
+            return Err(Error::DocumentDeleted);
+        }
+        if document.version != envelope.version {
+            return Err(Error::VersionConflict { current: document.version });
+        }
+
+        let signer = &mut envelope.signers[position];
+        signer.status = SignerStatus::Signed;
+        signer.acted_at = Some(time());
+        if position + 1 == envelope.signers.len() {
+            close(&mut envelope, EnvelopeStatus::Completed);
+        }
+        save(&envelope);
+        Ok(envelope)
+    })
+}
+
+// Decline an envelope whose turn is the caller's; this ends the envelope
+#[ic_cdk::update]
+fn decline_envelope(id: u64, reason: String) -> Result<Envelope, Error> {
+    audited_envelope(AuditOperation::DeclineEnvelope, id, || {
+        authenticate()?;
+        let mut envelope = pending_envelope(id)?;
+        let position = current_position(&envelope, &caller())?;
+        validate_reason(&reason)?;
+
+        let signer = &mut envelope.signers[position];
+        signer.status = SignerStatus::Declined;
+        signer.acted_at = Some(time());
+        signer.reason = Some(reason);
+        close(&mut envelope, EnvelopeStatus::Declined);
+        save(&envelope);
+        Ok(envelope)
+    })
+}
+
+// Withdraw a pending envelope; its sender or the document's owners only
+#[ic_cdk::update]
+fn void_envelope(id: u64, reason: String) -> Result<Envelope, Error> {
+    audited_envelope(AuditOperation::VoidEnvelope, id, || {
+        authenticate()?;
+        let mut envelope = pending_envelope(id)?;
+        if envelope.sender != caller() {
+            authorize(&load_document(envelope.document_id)?, Role::Owner)?;
+        }
+        validate_reason(&reason)?;
+
+        envelope.void_reason = Some(reason);
+        close(&mut envelope, EnvelopeStatus::Voided);
+        save(&envelope);
+        Ok(envelope)
+    })
+}
+
+// Retrieve an envelope; visible to its sender, its signers and readers of the document
+#[ic_cdk::query]
+fn get_envelope(id: u64) -> Result<Envelope, Error> {
+    let envelope = load_envelope(id)?;
+    let caller = caller();
+    let involved = envelope.sender == caller
+        || envelope.signers.iter().any(|signer| signer.principal == caller);
+    if !involved {
+        authorize(&load_document(envelope.document_id)?, Role::Reader)?;
+    }
+    Ok(envelope)
+}
+
+// Envelopes sent for a document, oldest first
+#[ic_cdk::query]
+fn list_document_envelopes(document_id: u64) -> Result<Vec<Envelope>, Error> {
+    let document = load_document(document_id)?;
+    authorize(&document, Role::Reader)?;
+    Ok(document_envelopes(document_id))
+}
+
+// Pending envelopes waiting for the caller's signature, oldest first
+#[ic_cdk::query]
+fn list_awaiting_my_signature() -> Vec<Envelope> {
+    let key = principal_key(&caller());
+    let ids: Vec<u64> = INBOX.with(|inbox| {
+        inbox
+            .borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.into_iter()
+        .filter_map(|id| load_envelope(id).ok())
+        .filter(|envelope| envelope.status == EnvelopeStatus::Pending)
+        .collect()
+}
+
+// Void the pending envelopes of a document once `version` has replaced the version
+// they were sent for
+pub(crate) fn void_superseded(document_id: u64, version: u64) {
+    for mut envelope in document_envelopes(document_id) {
+        if envelope.status == EnvelopeStatus::Pending {
+            envelope.void_reason = Some(format!("Superseded by version {}", version));
+            close(&mut envelope, EnvelopeStatus::Voided);
+            save(&envelope);
+        }
+    }
+}
+
+// Drop the envelopes of a document that no longer exists
+pub(crate) fn remove_envelopes(document_id: u64) {
+    for envelope in document_envelopes(document_id) {
+        remove_from_inbox(&envelope);
+        let deadline = (envelope.expires_at, envelope.id);
+        DEADLINES.with(|deadlines| deadlines.borrow_mut().remove(&deadline));
+        DOCUMENT_ENVELOPES
+            .with(|index| index.borrow_mut().remove(&(document_id, envelope.id)));
+        ENVELOPES.with(|envelopes| envelopes.borrow_mut().remove(&envelope.id));
+    }
+}
+
+// Index the stored envelopes; run by the schema migration that introduced the indexes
+pub(crate) fn rebuild_indexes() {
+    let envelopes: Vec<Envelope> = ENVELOPES
+        .with(|envelopes| envelopes.borrow().iter().map(|(_, envelope)| envelope).collect());
+    for envelope in envelopes {
+        save(&envelope);
+    }
+}
+
+// Envelopes sent for a document, oldest first
+fn document_envelopes(document_id: u64) -> Vec<Envelope> {
+    let ids: Vec<u64> = DOCUMENT_ENVELOPES.with(|index| {
+        index
+            .borrow()
+            .range((document_id, 0)..=(document_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.into_iter().filter_map(|id| load_envelope(id).ok()).collect()
+}
+
+// Audit an operation on an envelope under the envelope's document, when it exists
+fn audited_envelope<T>(
+    operation: AuditOperation,
+    id: u64,
+    call: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let document_id =
+        ENVELOPES.with(|envelopes| envelopes.borrow().get(&id)).map(|envelope| envelope.document_id);
+    audit::audited(operation, document_id, call)
+}
+
+fn load_envelope(id: u64) -> Result<Envelope, Error> {
+    ENVELOPES
+        .with(|envelopes| envelopes.borrow().get(&id))
+        .map(refresh)
+        .ok_or_else(|| Error::NotFound { msg: format!("Envelope with id={} not found", id) })
+}
+
+fn pending_envelope(id: u64) -> Result<Envelope, Error> {
+    let envelope = load_envelope(id)?;
+    if envelope.status != EnvelopeStatus::Pending {
+        return Err(Error::InvalidArgument {
+            msg: format!("Envelope {} is no longer pending", id),
+        });
+    }
+    Ok(envelope)
+}
+
+// Position of `principal` in the signing order if it is their turn
+fn current_position(envelope: &Envelope, principal: &Principal) -> Result<usize, Error> {
+    match current_signer(envelope) {
+        Some(position) if envelope.signers[position].principal == *principal => Ok(position),
+        _ => Err(Error::Unauthorized {
+            msg: format!("It is not the caller's turn to sign envelope {}", envelope.id),
+        }),
+    }
+}
+
+fn current_signer(envelope: &Envelope) -> Option<usize> {
+    envelope
+        .signers
+        .iter()
+        .position(|signer| signer.status == SignerStatus::Waiting)
+}
+
+fn validate_reason(reason: &str) -> Result<(), Error> {
+    if reason.trim().is_empty() {
+        return Err(Error::InvalidArgument { msg: "Reason must not be empty".to_string() });
+    }
+    if reason.len() > MAX_REASON_LEN {
+        return Err(Error::PayloadTooLarge {
+            field: "reason".to_string(),
+            limit: MAX_REASON_LEN as u64,
+        });
+    }
+    Ok(())
+}
+
+// Report a pending envelope past its deadline as expired, even before the timer
+// has stored it that way
+fn refresh(mut envelope: Envelope) -> Envelope {
+    if envelope.status == EnvelopeStatus::Pending && envelope.expires_at <= time() {
+        envelope.status = EnvelopeStatus::Expired;
+        envelope.closed_at = Some(envelope.expires_at);
+    }
+    envelope
+}
+
+fn close(envelope: &mut Envelope, status: EnvelopeStatus) {
+    envelope.status = status;
+    envelope.closed_at = Some(time());
+}
+
+// Store the envelope, point the inbox at its current signer, if any, and keep its
+// deadline only while it is pending
+fn save(envelope: &Envelope) {
+    remove_from_inbox(envelope);
+    let deadline = (envelope.expires_at, envelope.id);
+    if envelope.status == EnvelopeStatus::Pending {
+        if let Some(position) = current_signer(envelope) {
+            let key = principal_key(&envelope.signers[position].principal);
+            INBOX.with(|inbox| inbox.borrow_mut().insert((key, envelope.id), ()));
+        }
+        DEADLINES.with(|deadlines| deadlines.borrow_mut().insert(deadline, ()));
+    } else {
+        DEADLINES.with(|deadlines| deadlines.borrow_mut().remove(&deadline));
+    }
+    DOCUMENT_ENVELOPES
+        .with(|index| index.borrow_mut().insert((envelope.document_id, envelope.id), ()));
+    ENVELOPES.with(|envelopes| envelopes.borrow_mut().insert(envelope.id, envelope.clone()));
+}
+
+fn remove_from_inbox(envelope: &Envelope) {
+    INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+        for signer in &envelope.signers {
+            inbox.remove(&(principal_key(&signer.principal), envelope.id));
+        }
+    });
+}
+
+fn expire_envelopes() {
+    let now = time();
+    let expired: Vec<u64> = DEADLINES.with(|deadlines| {
+        deadlines.borrow().range((0, 0)..=(now, u64::MAX)).map(|((_, id), _)| id).collect()
+    });
+    for envelope in expired.into_iter().filter_map(|id| load_envelope(id).ok()) {
+        save(&envelope);
+    }
+}
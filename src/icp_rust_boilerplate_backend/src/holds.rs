@@ -0,0 +1,261 @@
+// Legal holds that freeze documents against edits, deletion and purging.
+//
+// Admins place a hold for a case on a list of documents or on every document matching
+// a filter at the time the hold is placed. While any hold on a document is active,
+// updates, reverts, soft deletes and purges are rejected with Error::UnderLegalHold.
+// Holds are never removed: lifting one records who lifted it, when and why, so the
+// hold history of every document stays available for review.
+use crate::audit::{self, AuditOperation};
+use crate::listing::{matches_filter, ListFilter};
+use crate::{
+    authorize_admin, load_document, schema, Error, Memory, MAX_REASON_LEN, MEMORY_MANAGER,
+    STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Maximum length, in bytes, of a case reference
+const MAX_CASE_REFERENCE_LEN: usize = 64;
+
+// Maximum number of documents a single hold can cover
+const MAX_HOLD_DOCUMENTS: usize = 10_000;
+
+// Maximum number of document ids returned by a single query
+const MAX_HOLD_PAGE_SIZE: u64 = 500;
+
+// Documents a hold is placed on
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum HoldScope {
+    Documents(Vec<u64>),
+    // Every document matching the filter when the hold is placed
+    Filter(ListFilter),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct HoldRelease {
+    lifted_by: Principal,
+    lifted_at: u64,
+    reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LegalHold {
+    id: u64,
+    case_reference: String,
+    reason: String,
+    // How the held documents were selected; explicit lists are not repeated here
+    filter: Option<ListFilter>,
+    document_count: u64,
+    placed_by: Principal,
+    placed_at: u64,
+    // Set once the hold is lifted
+    released: Option<HoldRelease>,
+}
+
+// Storable trait for LegalHold
+impl Storable for LegalHold {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for LegalHold
+impl BoundedStorable for LegalHold {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static HOLD_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), 0)
+            .expect("Cannot create the legal hold counter")
+    );
+
+    static HOLDS: RefCell<StableBTreeMap<u64, LegalHold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // Holds ever placed on a document, keyed by (document id, hold id)
+    static DOCUMENT_HOLDS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    // Documents covered by a hold, keyed by (hold id, document id)
+    static HOLD_DOCUMENTS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+}
+
+// Place a hold for a case on the documents in `scope`; admin only
+#[ic_cdk::update]
+fn place_legal_hold(
+    scope: HoldScope,
+    case_reference: String,
+    reason: String,
+) -> Result<LegalHold, Error> {
+    audit::audited(AuditOperation::PlaceLegalHold, None, || {
+        authorize_admin()?;
+        place(scope, case_reference, reason)
+    })
+}
+
+// Lift an active hold; admin only
+#[ic_cdk::update]
+fn lift_legal_hold(id: u64, reason: String) -> Result<LegalHold, Error> {
+    audit::audited(AuditOperation::LiftLegalHold, None, || {
+        authorize_admin()?;
+        validate_text("reason", &reason, MAX_REASON_LEN)?;
+        let mut hold = load_hold(id)?;
+        if hold.released.is_some() {
+            return Err(Error::InvalidArgument {
+                msg: format!("Legal hold {} is already lifted", id),
+            });
+        }
+        hold.released = Some(HoldRelease { lifted_by: caller(), lifted_at: time(), reason });
+        HOLDS.with(|holds| holds.borrow_mut().insert(id, hold.clone()));
+        Ok(hold)
+    })
+}
+
+// Every hold ever placed, lifted ones included, oldest first; admin only
+#[ic_cdk::query]
+fn list_legal_holds() -> Result<Vec<LegalHold>, Error> {
+    authorize_admin()?;
+    Ok(HOLDS.with(|holds| holds.borrow().iter().map(|(_, hold)| hold).collect()))
+}
+
+// Holds placed on a document, lifted ones included, oldest first; admin only
+#[ic_cdk::query]
+fn list_document_holds(document_id: u64) -> Result<Vec<LegalHold>, Error> {
+    authorize_admin()?;
+    Ok(holds_of(document_id))
+}
+
+// Page through the ids of the documents covered by a hold, starting at `from_id`;
+// admin only
+#[ic_cdk::query]
+fn list_held_documents(id: u64, from_id: u64, limit: u64) -> Result<Vec<u64>, Error> {
+    authorize_admin()?;
+    load_hold(id)?;
+
+    let limit = limit.clamp(1, MAX_HOLD_PAGE_SIZE) as usize;
+    Ok(HOLD_DOCUMENTS.with(|documents| {
+        documents
+            .borrow()
+            .range((id, from_id)..=(id, u64::MAX))
+            .take(limit)
+            .map(|((_, document_id), _)| document_id)
+            .collect()
+    }))
+}
+
+// Fail with Error::UnderLegalHold if an active hold covers the document
+pub(crate) fn ensure_not_held(document_id: u64) -> Result<(), Error> {
+    match holds_of(document_id).into_iter().find(|hold| hold.released.is_none()) {
+        Some(hold) => Err(Error::UnderLegalHold {
+            hold_id: hold.id,
+            case_reference: hold.case_reference,
+        }),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn is_held(document_id: u64) -> bool {
+    ensure_not_held(document_id).is_err()
+}
+
+fn place(scope: HoldScope, case_reference: String, reason: String) -> Result<LegalHold, Error> {
+    validate_text("case_reference", &case_reference, MAX_CASE_REFERENCE_LEN)?;
+    validate_text("reason", &reason, MAX_REASON_LEN)?;
+
+    let (mut document_ids, filter) = match scope {
+        HoldScope::Documents(ids) => {
+            for id in &ids {
+                load_document(*id)?;
+            }
+            (ids, None)
+        }
+        HoldScope::Filter(filter) => {
+            let ids = STORAGE.with(|service| {
+                service
+                    .borrow()
+                    .iter()
+                    .filter_map(schema::decoded)
+                    .filter(|(_, document)| matches_filter(document, &filter))
+                    .map(|(id, _)| id)
+                    .take(MAX_HOLD_DOCUMENTS + 1)
+                    .collect()
+            });
+            (ids, Some(filter))
+        }
+    };
+    document_ids.sort();
+    document_ids.dedup();
+    if document_ids.is_empty() || document_ids.len() > MAX_HOLD_DOCUMENTS {
+        return Err(Error::InvalidArgument {
+            msg: format!("A hold must cover between 1 and {} documents", MAX_HOLD_DOCUMENTS),
+        });
+    }
+
+    let id = HOLD_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1).map(|_| current_value)
+        })
+        .expect("cannot increment the legal hold counter");
+
+    let hold = LegalHold {
+        id,
+        case_reference,
+        reason,
+        filter,
+        document_count: document_ids.len() as u64,
+        placed_by: caller(),
+        placed_at: time(),
+        released: None,
+    };
+    HOLDS.with(|holds| holds.borrow_mut().insert(id, hold.clone()));
+    for document_id in document_ids {
+        DOCUMENT_HOLDS.with(|holds| holds.borrow_mut().insert((document_id, id), ()));
+        HOLD_DOCUMENTS.with(|documents| documents.borrow_mut().insert((id, document_id), ()));
+    }
+    Ok(hold)
+}
+
+fn load_hold(id: u64) -> Result<LegalHold, Error> {
+    HOLDS
+        .with(|holds| holds.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound { msg: format!("Legal hold with id={} not found", id) })
+}
+
+fn holds_of(document_id: u64) -> Vec<LegalHold> {
+    let ids: Vec<u64> = DOCUMENT_HOLDS.with(|holds| {
+        holds
+            .borrow()
+            .range((document_id, 0)..=(document_id, u64::MAX))
+            .map(|((_, hold_id), _)| hold_id)
+            .collect()
+    });
+    ids.into_iter().filter_map(|id| load_hold(id).ok()).collect()
+}
+
+// `field` is the name of the argument being checked
+fn validate_text(field: &str, text: &str, max_len: usize) -> Result<(), Error> {
+    if text.trim().is_empty() {
+        return Err(Error::InvalidArgument { msg: format!("{} must not be empty", field) });
+    }
+    if text.len() > max_len {
+        return Err(Error::PayloadTooLarge { field: field.to_string(), limit: max_len as u64 });
+    }
+    Ok(())
+}
@@ -0,0 +1,485 @@
+// ICRC-3 block log of document mutations.
+//
+// Creating, updating, deleting, restoring and purging documents and changing their
+// permissions each append a block. A block is an ICRC-3 `Value` map holding the block
+// type, the timestamp, the hash of the previous block and the mutation itself, hashed
+// with the ICRC-3 representation-independent hash. The index and hash of the latest
+// block are part of the canister's certified data, so indexers can verify the log
+// from `icrc3_get_tip_certificate` back to the first block.
+//
+// Admins can move the oldest blocks to an archive canister with `archive_blocks`; the
+// archive must implement `append_blocks(nat, vec Value)` and `icrc3_get_blocks`, and
+// `icrc3_get_blocks` here points callers at it for the archived ranges.
+use crate::audit::{self, AuditOperation};
+use crate::{authorize_admin, certification, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Int, Nat, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Block types of document mutations
+pub(crate) const BTYPE_CREATE: &str = "doc_create";
+pub(crate) const BTYPE_UPDATE: &str = "doc_update";
+pub(crate) const BTYPE_DELETE: &str = "doc_delete";
+pub(crate) const BTYPE_RESTORE: &str = "doc_restore";
+pub(crate) const BTYPE_PURGE: &str = "doc_purge";
+pub(crate) const BTYPE_GRANT: &str = "doc_grant";
+pub(crate) const BTYPE_REVOKE: &str = "doc_revoke";
+
+const BLOCK_TYPES: [&str; 7] = [
+    BTYPE_CREATE,
+    BTYPE_UPDATE,
+    BTYPE_DELETE,
+    BTYPE_RESTORE,
+    BTYPE_PURGE,
+    BTYPE_GRANT,
+    BTYPE_REVOKE,
+];
+
+const ICRC3_URL: &str = "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3";
+
+// Maximum number of blocks returned by a single icrc3_get_blocks call
+const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+
+// Maximum number of blocks sent to an archive by a single archive_blocks call
+const MAX_ARCHIVE_BATCH: u64 = 1_000;
+
+// ICRC-3 generic value
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum Value {
+    Blob(ByteBuf),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub(crate) fn nat(value: u64) -> Self {
+        Value::Nat(Nat::from(value))
+    }
+
+    pub(crate) fn blob(bytes: &[u8]) -> Self {
+        Value::Blob(ByteBuf::from(bytes.to_vec()))
+    }
+
+    pub(crate) fn text(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+// Storable trait for Value
+impl Storable for Value {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for Value
+impl BoundedStorable for Value {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Length of the log and hash of its latest block
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct BlockTip {
+    length: u64,
+    hash: ByteBuf,
+}
+
+// Storable trait for BlockTip
+impl Storable for BlockTip {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+candid::define_function!(
+    pub(crate) GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query
+);
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub(crate) struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: GetBlocksCallback,
+}
+
+#[derive(candid::CandidType, Clone, Deserialize)]
+pub(crate) struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct GetArchivesArgs {
+    // Only list archives after this one
+    from: Option<Principal>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchiveInfo {
+    canister_id: Principal,
+    // Inclusive range of block ids held by the archive
+    start: Nat,
+    end: Nat,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DataCertificate {
+    certificate: ByteBuf,
+    // CBOR-encoded hash tree revealing last_block_index and last_block_hash
+    hash_tree: ByteBuf,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SupportedBlockType {
+    block_type: String,
+    url: String,
+}
+
+// Blocks moved to an archive canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchivedRange {
+    canister_id: Principal,
+    start: u64,
+    length: u64,
+}
+
+// Storable trait for ArchivedRange
+impl Storable for ArchivedRange {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for ArchivedRange
+impl BoundedStorable for ArchivedRange {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Blocks still held by this canister, keyed by block id
+    static BLOCKS: RefCell<StableBTreeMap<u64, Value, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    // Archived ranges keyed by their first block id
+    static ARCHIVES: RefCell<StableBTreeMap<u64, ArchivedRange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    static TIP: RefCell<Cell<BlockTip, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))), BlockTip::default())
+            .expect("Cannot create the block log tip")
+    );
+
+    // Set while blocks are on their way to an archive
+    static ARCHIVING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+// Blocks in the requested ranges; blocks that were archived are left to the callbacks
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let log_length = tip().length;
+    let mut blocks = Vec::new();
+    let mut archived_blocks = Vec::new();
+
+    for range in args {
+        let start = to_u64(&range.start);
+        let end = start.saturating_add(to_u64(&range.length)).min(log_length);
+        if start >= end {
+            continue;
+        }
+
+        ARCHIVES.with(|archives| {
+            for (_, archive) in archives.borrow().iter() {
+                let archive_end = archive.start + archive.length;
+                let (from, to) = (start.max(archive.start), end.min(archive_end));
+                if from < to {
+                    let length = Nat::from(to - from);
+                    archived_blocks.push(ArchivedBlocks {
+                        args: vec![GetBlocksArgs { start: Nat::from(from), length }],
+                        callback: GetBlocksCallback::new(
+                            archive.canister_id,
+                            "icrc3_get_blocks".to_string(),
+                        ),
+                    });
+                }
+            }
+        });
+
+        BLOCKS.with(|stored| {
+            for (id, block) in stored.borrow().range(start..end) {
+                if blocks.len() as u64 == MAX_BLOCKS_PER_RESPONSE {
+                    break;
+                }
+                blocks.push(BlockWithId { id: Nat::from(id), block });
+            }
+        });
+    }
+
+    GetBlocksResult { log_length: Nat::from(log_length), blocks, archived_blocks }
+}
+
+// Archive canisters holding older blocks, in block order
+#[ic_cdk::query]
+fn icrc3_get_archives(args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    ARCHIVES.with(|archives| {
+        archives
+            .borrow()
+            .iter()
+            .map(|(_, archive)| archive)
+            .skip_while(|archive| args.from.is_some_and(|from| archive.canister_id != from))
+            .skip(usize::from(args.from.is_some()))
+            .map(|archive| ArchiveInfo {
+                canister_id: archive.canister_id,
+                start: Nat::from(archive.start),
+                end: Nat::from(archive.start + archive.length - 1),
+            })
+            .collect()
+    })
+}
+
+// Certificate over the index and hash of the latest block; none until a block exists
+#[ic_cdk::query]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let hash_tree = certification::tip_witness()?;
+    Some(DataCertificate {
+        certificate: ByteBuf::from(certificate),
+        hash_tree: ByteBuf::from(hash_tree),
+    })
+}
+
+#[ic_cdk::query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    BLOCK_TYPES
+        .iter()
+        .map(|block_type| SupportedBlockType {
+            block_type: block_type.to_string(),
+            url: ICRC3_URL.to_string(),
+        })
+        .collect()
+}
+
+// Move up to `max_blocks` of the oldest blocks to an archive canister; admin only
+#[ic_cdk::update]
+async fn archive_blocks(archive: Principal, max_blocks: u64) -> Result<ArchiveInfo, Error> {
+    let result = archive_to(archive, max_blocks).await;
+    audit::record_result(AuditOperation::ArchiveBlocks, None, &result);
+    result
+}
+
+async fn archive_to(archive: Principal, max_blocks: u64) -> Result<ArchiveInfo, Error> {
+    authorize_admin()?;
+    if ARCHIVING.with(|archiving| archiving.replace(true)) {
+        return Err(Error::InvalidArgument {
+            msg: "Blocks are already being archived".to_string(),
+        });
+    }
+
+    let batch: Vec<(u64, Value)> = BLOCKS.with(|blocks| {
+        blocks
+            .borrow()
+            .iter()
+            .take(max_blocks.clamp(1, MAX_ARCHIVE_BATCH) as usize)
+            .collect()
+    });
+    let Some(start) = batch.first().map(|(id, _)| *id) else {
+        ARCHIVING.with(|archiving| archiving.set(false));
+        return Err(Error::NotFound { msg: "There are no blocks to archive".to_string() });
+    };
+    let length = batch.len() as u64;
+    let values: Vec<Value> = batch.into_iter().map(|(_, block)| block).collect();
+
+    let sent: Result<(), _> =
+        ic_cdk::call(archive, "append_blocks", (Nat::from(start), values)).await;
+    ARCHIVING.with(|archiving| archiving.set(false));
+    sent.map_err(|(code, msg)| Error::InvalidArgument {
+        msg: format!("Archive {} rejected the blocks: {:?}: {}", archive, code, msg),
+    })?;
+
+    BLOCKS.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        for id in start..start + length {
+            blocks.remove(&id);
+        }
+    });
+    let range = ArchivedRange { canister_id: archive, start, length };
+    ARCHIVES.with(|archives| archives.borrow_mut().insert(start, range));
+    Ok(ArchiveInfo {
+        canister_id: archive,
+        start: Nat::from(start),
+        end: Nat::from(start + length - 1),
+    })
+}
+
+// Append a block for a mutation of a document made by the caller
+pub(crate) fn log_document_block(btype: &str, document_id: u64, fields: Vec<(&str, Value)>) {
+    let mut tx = vec![
+        ("doc".to_string(), Value::nat(document_id)),
+        ("caller".to_string(), Value::blob(caller().as_slice())),
+    ];
+    tx.extend(fields.into_iter().map(|(name, value)| (name.to_string(), value)));
+
+    let tip = tip();
+    let mut block = vec![
+        ("btype".to_string(), Value::text(btype)),
+        ("ts".to_string(), Value::nat(time())),
+    ];
+    if tip.length > 0 {
+        block.push(("phash".to_string(), Value::Blob(tip.hash)));
+    }
+    block.push(("tx".to_string(), Value::Map(tx)));
+    let block = Value::Map(block);
+
+    let hash = hash_value(&block);
+    BLOCKS.with(|blocks| blocks.borrow_mut().insert(tip.length, block));
+    let next = BlockTip { length: tip.length + 1, hash: ByteBuf::from(hash.to_vec()) };
+    TIP.with(|cell| cell.borrow_mut().set(next))
+        .expect("cannot update the block log tip");
+    certification::certify_tip(tip.length, hash);
+}
+
+// Index and hash of the latest block, if any
+pub(crate) fn last_block() -> Option<(u64, [u8; 32])> {
+    let tip = tip();
+    let hash = <[u8; 32]>::try_from(tip.hash.as_slice()).ok()?;
+    tip.length.checked_sub(1).map(|index| (index, hash))
+}
+
+// ICRC-3 representation-independent hash of a value
+pub(crate) fn hash_value(value: &Value) -> [u8; 32] {
+    match value {
+        Value::Blob(bytes) => Sha256::digest(bytes).into(),
+        Value::Text(text) => Sha256::digest(text.as_bytes()).into(),
+        Value::Nat(nat) => {
+            let mut leb128 = Vec::new();
+            nat.encode(&mut leb128).expect("cannot encode a nat");
+            Sha256::digest(leb128).into()
+        }
+        Value::Int(int) => {
+            let mut sleb128 = Vec::new();
+            int.encode(&mut sleb128).expect("cannot encode an int");
+            Sha256::digest(sleb128).into()
+        }
+        Value::Array(values) => {
+            let mut hasher = Sha256::new();
+            for value in values {
+                hasher.update(hash_value(value));
+            }
+            hasher.finalize().into()
+        }
+        Value::Map(entries) => {
+            let mut pairs: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut pair = Sha256::digest(key.as_bytes()).to_vec();
+                    pair.extend_from_slice(&hash_value(value));
+                    pair
+                })
+                .collect();
+            pairs.sort();
+            let mut hasher = Sha256::new();
+            for pair in pairs {
+                hasher.update(pair);
+            }
+            hasher.finalize().into()
+        }
+    }
+}
+
+fn tip() -> BlockTip {
+    TIP.with(|cell| cell.borrow().get().clone())
+}
+
+fn to_u64(nat: &Nat) -> u64 {
+    u64::try_from(&nat.0).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hash: [u8; 32]) -> String {
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // Examples from the representation-independent hashing section of the ICRC-3 standard
+    #[test]
+    fn hashes_the_standard_examples() {
+        let cases = [
+            (Value::nat(42), "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"),
+            (
+                Value::Int(Int::from(-42)),
+                "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc",
+            ),
+            (
+                Value::text("Hello, World!"),
+                "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f",
+            ),
+            (
+                Value::blob(&[1, 2, 3, 4]),
+                "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a",
+            ),
+            (
+                Value::Array(vec![Value::nat(3), Value::text("foo"), Value::blob(&[5, 6])]),
+                "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6",
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(hex(hash_value(&value)), expected);
+        }
+
+        let account = |second: u8| {
+            Value::blob(&[
+                0x00, 0xab, second, 0xef, 0x00, 0x12, 0x34, 0x00, 0x56, 0x78, 0x9a, 0x00, 0xbc,
+                0xde, 0xf0, 0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0x00, 0xab, 0xcd, 0xef, 0x01,
+            ])
+        };
+        let map = Value::Map(vec![
+            ("from".to_string(), account(0xcd)),
+            ("to".to_string(), account(0x0d)),
+            ("amount".to_string(), Value::nat(42)),
+            ("created_at".to_string(), Value::nat(1699218263)),
+            ("memo".to_string(), Value::nat(0)),
+        ]);
+        assert_eq!(
+            hex(hash_value(&map)),
+            "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75"
+        );
+    }
+}
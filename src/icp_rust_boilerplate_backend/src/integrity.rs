@@ -0,0 +1,120 @@
+// Tamper-evident hash chain over a document's version history.
+//
+// Each DocumentVersion stores the hash of the previous version and its own hash,
+// computed as SHA-256 over the previous hash followed by a canonical encoding of the
+// version's fields. The latest hash is kept on the Document as `history_head`, so
+// rewriting or dropping any version breaks the chain from that point on.
+use crate::{readable_document, Document, DocumentVersion, Error, HISTORY};
+use sha2::{Digest, Sha256};
+
+// Hash that the first version of every document chains onto
+pub(crate) const GENESIS_HASH: [u8; 32] = [0; 32];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct HistoryVerification {
+    document_id: u64,
+    versions_checked: u64,
+    valid: bool,
+    // First version whose stored hashes do not match the recomputed chain
+    first_broken_version: Option<u64>,
+    reason: Option<String>,
+}
+
+// Recompute the hash chain of a document and report the first broken link
+#[ic_cdk::query]
+fn verify_document_history(id: u64) -> Result<HistoryVerification, Error> {
+    let document = readable_document(id)?;
+    let mut versions_checked = 0;
+    let result = check_chain(&document, &mut versions_checked);
+
+    Ok(HistoryVerification {
+        document_id: id,
+        versions_checked,
+        valid: result.is_ok(),
+        first_broken_version: result.as_ref().err().map(|(version, _)| *version),
+        reason: result.err().map(|(_, reason)| reason),
+    })
+}
+
+// Walk the stored versions in order, failing with the first broken version and why
+pub(crate) fn check_chain(document: &Document, versions_checked: &mut u64) -> Result<(), (u64, String)> {
+    let mut previous_hash = GENESIS_HASH.to_vec();
+    let mut expected = 1;
+
+    let id = document.id;
+    HISTORY.with(|history| {
+        for ((_, number), stored) in history.borrow().range((id, 0)..=(id, u64::MAX)) {
+            *versions_checked += 1;
+            let Ok(version) = stored.decode(id) else {
+                return Err((number, format!("Version {} cannot be decoded", number)));
+            };
+            if number != expected || version.version != number {
+                return Err((expected, format!("Version {} is missing", expected)));
+            }
+            if version.previous_hash.as_slice() != previous_hash.as_slice() {
+                let reason = "Previous hash does not match the prior version";
+                return Err((number, reason.to_string()));
+            }
+            let hash = version_hash(&version);
+            if version.hash.as_slice() != hash.as_slice() {
+                let reason = "Stored hash does not match the version content";
+                return Err((number, reason.to_string()));
+            }
+            previous_hash = hash.to_vec();
+            expected += 1;
+        }
+        Ok(())
+    })?;
+
+    if expected != document.version + 1 {
+        return Err((expected, format!("Version {} is missing", expected)));
+    }
+    if document.history_head.as_slice() != previous_hash.as_slice() {
+        return Err((
+            document.version,
+            "History head does not match the latest version".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// SHA-256 over the previous hash and the length-prefixed fields of the version;
+// optional fields are preceded by a presence byte, except the trailing lifecycle
+pub(crate) fn version_hash(version: &DocumentVersion) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    write_field(&mut hasher, &version.previous_hash);
+    write_field(&mut hasher, &version.version.to_be_bytes());
+    write_field(&mut hasher, version.title.as_bytes());
+    write_field(&mut hasher, version.description.as_bytes());
+    write_field(&mut hasher, version.file_url.as_bytes());
+    let content_id = version.content_id.map(u64::to_be_bytes);
+    write_optional(&mut hasher, content_id.as_ref().map(|id| &id[..]));
+    write_optional(&mut hasher, version.metadata.display_name.as_ref().map(String::as_bytes));
+    write_field(&mut hasher, version.metadata.change_summary.as_bytes());
+    write_field(&mut hasher, version.updated_by.as_slice());
+    write_field(&mut hasher, &version.updated_at.to_be_bytes());
+    // Only hashed when present, so versions recorded before lifecycles keep their hash
+    if let Some(lifecycle) = &version.lifecycle {
+        write_field(&mut hasher, lifecycle.state.name().as_bytes());
+        write_field(&mut hasher, &(lifecycle.approved_by.len() as u64).to_be_bytes());
+        for reviewer in &lifecycle.approved_by {
+            write_field(&mut hasher, reviewer.as_slice());
+        }
+    }
+    hasher.finalize().into()
+}
+
+pub(crate) fn write_field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+pub(crate) fn write_optional(hasher: &mut Sha256, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            hasher.update([1]);
+            write_field(hasher, bytes);
+        }
+        None => hasher.update([0]),
+    }
+}
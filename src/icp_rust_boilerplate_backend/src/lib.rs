@@ -1,43 +1,157 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use audit::{AuditFilter, AuditOperation, AuditPage};
+use candid::{Decode, Encode, Principal};
+use certification::CertifiedDocument;
+use changes::{ChangeKind, ChangePage};
+use content::{ContentInfo, UploadStatus};
+use diff::VersionDiff;
+use envelopes::Envelope;
+use holds::{HoldScope, LegalHold};
+use icrc3::{
+    ArchiveInfo, DataCertificate, GetArchivesArgs, GetBlocksArgs, GetBlocksResult,
+    SupportedBlockType, Value,
+};
+use integrity::HistoryVerification;
+use lifecycle::{DocumentLifecycle, LifecyclePolicy, LifecycleRecord, LifecycleState};
+use ic_cdk::api::{caller, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Blob;
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use listing::{DocumentPage, ListCursor, ListRequest};
+use locks::Lock;
+use notarization::{Notarization, NotarizationReceipt};
+use schema::{SchemaInfo, StoredDocument, StoredVersion};
+use search::{SearchMode, SearchResult};
+use serde_bytes::ByteBuf;
+use signatures::{SignatureScheme, VersionSignature};
+use timeline::TimelineSearchPage;
+use std::{borrow::Cow, cell::RefCell, fmt};
+
+mod audit;
+mod certification;
+mod changes;
+mod content;
+mod diff;
+mod envelopes;
+mod holds;
+mod icrc3;
+mod integrity;
+mod lifecycle;
+mod listing;
+mod locks;
+mod notarization;
+mod retention;
+mod schema;
+mod search;
+mod signatures;
+mod timeline;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type PrincipalKey = Blob<29>;
 
-// Metadata for document updates
+// Client-supplied metadata for document updates; `display_name` is a label only,
+// attribution always comes from the authenticated caller
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct DocumentMetadata {
-    updated_by: String,
+    display_name: Option<String>,
     change_summary: String,
 }
 
+// Access level a principal holds on a document, ordered from least to most privileged
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
+enum Role {
+    #[default]
+    Reader,
+    Editor,
+    Owner,
+}
+
+impl Role {
+    fn name(&self) -> &'static str {
+        match self {
+            Role::Reader => "Reader",
+            Role::Editor => "Editor",
+            Role::Owner => "Owner",
+        }
+    }
+}
+
+// Entry of a document's access control list
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Permission {
+    principal: Principal,
+    role: Role,
+}
+
 // Document struct stored in stable storage
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct Document {
     id: u64,
+    owner: Principal,
     title: String,
     description: String,
     file_url: String,
+    content_id: Option<u64>,
     version: u64,
     created_at: u64,
     updated_at: Option<u64>,
+    updated_by: Option<Principal>,
     is_deleted: bool,
-    history: Vec<DocumentVersion>,
+    // Set while the document is soft-deleted
+    deletion: Option<DeletionRecord>,
+    // Hash of the latest version, the head of the history hash chain
+    history_head: ByteBuf,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+// Who soft-deleted a document, when and why
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DeletionRecord {
+    deleted_by: Principal,
+    deleted_at: u64,
+    reason: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct DocumentVersion {
     version: u64,
     title: String,
     description: String,
     file_url: String,
+    content_id: Option<u64>,
     metadata: DocumentMetadata,
+    updated_by: Principal,
     updated_at: u64,
+    // Lifecycle state the document entered with this version; absent on versions
+    // recorded before lifecycles existed
+    lifecycle: Option<LifecycleRecord>,
+    // Hash chain linking this version to the previous one
+    previous_hash: ByteBuf,
+    hash: ByteBuf,
+}
+
+// Lifecycle events that are not captured by a new DocumentVersion
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum DocumentEventKind {
+    Created,
+    Deleted,
+    Restored,
+}
+
+// Lifecycle event attributed to the caller that triggered it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DocumentEvent {
+    kind: DocumentEventKind,
+    actor: Principal,
+    timestamp: u64,
+}
+
+// A page of a document's version history
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VersionPage {
+    versions: Vec<DocumentVersion>,
+    next_version: Option<u64>,
 }
 
 // Document payload for creating or updating a document
@@ -46,12 +160,33 @@ struct DocumentPayload {
     title: String,
     description: String,
     file_url: String,
+    // Committed upload to attach as the document's content
+    content_id: Option<u64>,
     metadata: DocumentMetadata,
+    // Version the client edited; required by update_document, ignored on creation
+    expected_version: Option<u64>,
+}
+
+// Storable trait for DocumentEvent
+impl Storable for DocumentEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-// Storable trait for Document
-impl Storable for Document {
-    fn to_bytes(&self) -> Cow<[u8]> {
+// BoundedStorable trait for DocumentEvent
+impl BoundedStorable for DocumentEvent {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable trait for Role
+impl Storable for Role {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -60,12 +195,49 @@ impl Storable for Document {
     }
 }
 
-// BoundedStorable trait for Document
-impl BoundedStorable for Document {
-    const MAX_SIZE: u32 = 1024;
+// BoundedStorable trait for Role
+impl BoundedStorable for Role {
+    const MAX_SIZE: u32 = 32;
     const IS_FIXED_SIZE: bool = false;
 }
 
+// Unit of the timestamps returned by `time()`
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Maximum number of versions returned by a single history query
+const MAX_VERSION_PAGE_SIZE: u64 = 100;
+
+// Maximum length, in bytes, of a deletion reason
+const MAX_REASON_LEN: usize = 256;
+
+// Maximum lengths, in bytes, of document payload fields. Together they keep a document
+// and its versions within the MAX_SIZE of their stable maps whatever the payload.
+const MAX_TITLE_LEN: usize = 256;
+const MAX_DESCRIPTION_LEN: usize = 2048;
+const MAX_FILE_URL_LEN: usize = 128;
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+const MAX_CHANGE_SUMMARY_LEN: usize = 512;
+
+// Size limits enforced by the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Limits {
+    max_title_len: u64,
+    max_description_len: u64,
+    max_file_url_len: u64,
+    max_display_name_len: u64,
+    max_change_summary_len: u64,
+    max_reason_len: u64,
+    // Largest stored records, in bytes
+    max_document_size: u64,
+    max_version_size: u64,
+    max_content_size: u64,
+    chunk_size: u64,
+    // Bytes a principal, and all principals together, can hold in content not yet
+    // attached to a document
+    max_unattached_bytes: u64,
+    max_total_unattached_bytes: u64,
+}
+
 // Thread-local storage
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
@@ -77,169 +249,766 @@ thread_local! {
             .expect("Cannot create a counter")
     );
 
-    static STORAGE: RefCell<StableBTreeMap<u64, Document, Memory>> =
+    // Documents keyed by id, in memory 1 before schema version 5
+    static STORAGE: RefCell<StableBTreeMap<u64, StoredDocument, Memory>> =
         RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
     ));
+
+    // Version history keyed by (document id, version), in memory 2 before schema version 5
+    static HISTORY: RefCell<StableBTreeMap<(u64, u64), StoredVersion, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    // Per-document access control lists keyed by (document id, principal)
+    static PERMISSIONS: RefCell<StableBTreeMap<(u64, PrincipalKey), Role, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    // Create/delete/restore events keyed by (document id, sequence within the document)
+    static EVENTS: RefCell<StableBTreeMap<(u64, u64), DocumentEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+}
+
+// Settings passed when the canister is installed or upgraded
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct InitArgs {
+    // Owner of the documents migrated from the original release, which recorded none;
+    // the controller performing the upgrade when unset
+    legacy_owner: Option<Principal>,
+    // Threshold ECDSA key used for notarization; kept across upgrades that leave it
+    // unset, `dfx_test_key` until first set
+    ecdsa_key_name: Option<String>,
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    apply_settings(args.unwrap_or_default());
+    schema::stamp_version();
+    start_timers();
+    certification::rebuild_tree();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    schema::stamp_version();
+}
+
+// Migrations run first so everything after them reads the current schema
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    let args = args.unwrap_or_default();
+    schema::migrate(args.legacy_owner.unwrap_or_else(caller));
+    apply_settings(args);
+    start_timers();
+    certification::rebuild_tree();
+}
+
+fn apply_settings(args: InitArgs) {
+    if let Some(name) = args.ecdsa_key_name {
+        notarization::set_key_name(name);
+    }
+}
+
+// Timers do not survive upgrades, so they are started on both install and upgrade
+fn start_timers() {
+    retention::start_timers();
+    locks::start_timers();
+    envelopes::start_timers();
+    content::start_timers();
+}
+
+// Size limits on payloads and stored records
+#[ic_cdk::query]
+fn get_limits() -> Limits {
+    Limits {
+        max_title_len: MAX_TITLE_LEN as u64,
+        max_description_len: MAX_DESCRIPTION_LEN as u64,
+        max_file_url_len: MAX_FILE_URL_LEN as u64,
+        max_display_name_len: MAX_DISPLAY_NAME_LEN as u64,
+        max_change_summary_len: MAX_CHANGE_SUMMARY_LEN as u64,
+        max_reason_len: MAX_REASON_LEN as u64,
+        max_document_size: schema::MAX_DOCUMENT_SIZE as u64,
+        max_version_size: schema::MAX_VERSION_SIZE as u64,
+        max_content_size: content::MAX_CONTENT_SIZE,
+        max_unattached_bytes: content::MAX_UNATTACHED_BYTES_PER_PRINCIPAL,
+        max_total_unattached_bytes: content::MAX_UNATTACHED_BYTES_TOTAL,
+        chunk_size: content::CHUNK_SIZE,
+    }
 }
 
 // Function to add multiple documents at once
 #[ic_cdk::update]
-fn add_documents(documents: Vec<DocumentPayload>) -> Vec<Document> {
+fn add_documents(documents: Vec<DocumentPayload>) -> Result<Vec<Document>, Error> {
+    let result = create_documents(documents);
+    match &result {
+        Ok(added_documents) => {
+            for document in added_documents {
+                audit::record_result(AuditOperation::CreateDocument, Some(document.id), &result);
+            }
+        }
+        Err(_) => audit::record_result(AuditOperation::CreateDocument, None, &result),
+    }
+    result
+}
+
+fn create_documents(documents: Vec<DocumentPayload>) -> Result<Vec<Document>, Error> {
+    authenticate()?;
+    // Validate every payload and attached content up front so the batch is all-or-nothing
+    for (index, payload) in documents.iter().enumerate() {
+        validate_payload(payload, &format!("documents[{}].", index))?;
+    }
+    let mut content_ids = Vec::new();
+    for content_id in documents.iter().filter_map(|payload| payload.content_id) {
+        if content_ids.contains(&content_id) {
+            return Err(Error::InvalidArgument {
+                msg: format!("Content {} is attached to more than one document", content_id),
+            });
+        }
+        content::check_attachable(content_id, None)?;
+        content_ids.push(content_id);
+    }
+
     let mut added_documents = Vec::new();
 
     for payload in documents {
-        let document = add_single_document(payload.clone());
+        let document = add_single_document(payload.clone())?;
         added_documents.push(document);
     }
 
-    added_documents
+    Ok(added_documents)
 }
 
-fn add_single_document(payload: DocumentPayload) -> Document {
+fn add_single_document(payload: DocumentPayload) -> Result<Document, Error> {
     let id = ID_COUNTER.with(|counter| {
         let current_value = *counter.borrow().get();
         counter.borrow_mut().set(current_value + 1)
     }).expect("cannot increment id counter");
 
-    let document = Document {
+    let mut document = Document {
         id,
+        owner: caller(),
         title: payload.title.clone(),
         description: payload.description.clone(),
         file_url: payload.file_url.clone(),
+        content_id: payload.content_id,
         version: 1,
         created_at: time(),
         updated_at: None,
+        updated_by: None,
         is_deleted: false,
-        history: vec![DocumentVersion {
+        deletion: None,
+        history_head: ByteBuf::from(integrity::GENESIS_HASH.to_vec()),
+    };
+
+    if let Some(content_id) = payload.content_id {
+        content::attach_content(content_id, id)?;
+    }
+
+    append_version(
+        &mut document,
+        DocumentVersion {
             version: 1,
             title: payload.title.clone(),
             description: payload.description.clone(),
             file_url: payload.file_url.clone(),
+            content_id: payload.content_id,
             metadata: payload.metadata.clone(),
+            updated_by: caller(),
             updated_at: time(),
-        }],
-    };
-
+            lifecycle: Some(lifecycle::draft_record()),
+            previous_hash: ByteBuf::new(),
+            hash: ByteBuf::new(),
+        },
+    );
     do_insert_document(&document);
-    document
+    search::index_document(&document);
+    record_event(document.id, DocumentEventKind::Created);
+    log_version_block(icrc3::BTYPE_CREATE, &document, Vec::new());
+    changes::record(id, ChangeKind::Created, document.version, None);
+    Ok(document)
+}
+
+// Reject payload fields longer than their limits, naming the field after `field_prefix`
+fn validate_payload(payload: &DocumentPayload, field_prefix: &str) -> Result<(), Error> {
+    let display_name_len = payload.metadata.display_name.as_ref().map_or(0, String::len);
+    let fields = [
+        ("title", payload.title.len(), MAX_TITLE_LEN),
+        ("description", payload.description.len(), MAX_DESCRIPTION_LEN),
+        ("file_url", payload.file_url.len(), MAX_FILE_URL_LEN),
+        ("metadata.display_name", display_name_len, MAX_DISPLAY_NAME_LEN),
+        ("metadata.change_summary", payload.metadata.change_summary.len(), MAX_CHANGE_SUMMARY_LEN),
+    ];
+    match fields.into_iter().find(|(_, len, limit)| len > limit) {
+        Some((field, _, limit)) => Err(Error::PayloadTooLarge {
+            field: format!("{}{}", field_prefix, field),
+            limit: limit as u64,
+        }),
+        None => Ok(()),
+    }
 }
 
 fn do_insert_document(document: &Document) {
-    STORAGE.with(|service| service.borrow_mut().insert(document.id, document.clone()));
+    let previous = STORAGE
+        .with(|service| service.borrow_mut().insert(document.id, StoredDocument::new(document)));
+    if let Some(Ok(previous)) = previous.map(|stored| stored.decode(document.id)) {
+        listing::unindex_document(&previous);
+    }
+    listing::index_document(document);
+    certification::certify_document(document);
+}
+
+// Remove a document with its history, events and permissions, returning the ids of
+// the contents it referenced
+fn do_remove_document(id: u64) -> Vec<u64> {
+    let mut content_ids = Vec::new();
+    let removed = STORAGE.with(|service| service.borrow_mut().remove(&id));
+    if let Some(Ok(document)) = removed.map(|stored| stored.decode(id)) {
+        listing::unindex_document(&document);
+        content_ids.extend(document.content_id);
+    }
+    certification::uncertify_document(id);
+
+    HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let versions: Vec<(u64, u64)> =
+            history.range((id, 0)..=(id, u64::MAX)).map(|(key, _)| key).collect();
+        for key in versions {
+            let removed = history.remove(&key).and_then(|stored| stored.decode(id).ok());
+            if let Some(content_id) = removed.and_then(|version| version.content_id) {
+                content_ids.push(content_id);
+            }
+        }
+    });
+    EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let keys: Vec<(u64, u64)> =
+            events.range((id, 0)..=(id, u64::MAX)).map(|(key, _)| key).collect();
+        for key in keys {
+            events.remove(&key);
+        }
+    });
+    PERMISSIONS.with(|acl| {
+        let mut acl = acl.borrow_mut();
+        let keys: Vec<(u64, PrincipalKey)> = acl
+            .range((id, PrincipalKey::default())..)
+            .take_while(|((document_id, _), _)| *document_id == id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            acl.remove(&key);
+        }
+    });
+    locks::remove_lock(id);
+    notarization::remove_notarizations(id);
+    signatures::remove_signatures(id);
+    envelopes::remove_envelopes(id);
+    lifecycle::remove_lifecycle(id);
+
+    content_ids.sort();
+    content_ids.dedup();
+    content_ids
+}
+
+// Chain the version onto the document's history and store it; the caller persists
+// the document with its new history head
+fn append_version(document: &mut Document, mut version: DocumentVersion) {
+    version.previous_hash = document.history_head.clone();
+    version.hash = ByteBuf::from(integrity::version_hash(&version).to_vec());
+    document.history_head = version.hash.clone();
+    do_insert_version(document.id, &version);
+}
+
+fn do_insert_version(document_id: u64, version: &DocumentVersion) {
+    HISTORY.with(|history| {
+        history
+            .borrow_mut()
+            .insert((document_id, version.version), StoredVersion::new(version))
+    });
+}
+
+// Append an ICRC-3 block for a new version of the document
+fn log_version_block(btype: &str, document: &Document, mut fields: Vec<(&str, Value)>) {
+    fields.push(("version", Value::nat(document.version)));
+    fields.push(("vhash", Value::blob(&document.history_head)));
+    icrc3::log_document_block(btype, document.id, fields);
+}
+
+// Append a lifecycle event for the document, attributed to the caller at the current time
+fn record_event(document_id: u64, kind: DocumentEventKind) {
+    EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let sequence = events
+            .range((document_id, 0)..=(document_id, u64::MAX))
+            .last()
+            .map_or(0, |((_, sequence), _)| sequence + 1);
+        events.insert(
+            (document_id, sequence),
+            DocumentEvent { kind, actor: caller(), timestamp: time() },
+        );
+    });
 }
 
 // Update a document and track version history with metadata
 #[ic_cdk::update]
 fn update_document(id: u64, payload: DocumentPayload) -> Result<Document, Error> {
-    STORAGE.with(|service| {
-        match service.borrow().get(&id) {
-            Some(mut document) => {
-                if document.is_deleted {
-                    return Err(Error::DocumentDeleted);
-                }
-
-                let new_version = document.version + 1;
-                let doc_version = DocumentVersion {
-                    version: new_version,
-                    title: payload.title.clone(),
-                    description: payload.description.clone(),
-                    file_url: payload.file_url.clone(),
-                    metadata: payload.metadata.clone(),
-                    updated_at: time(),
-                };
-                document.history.push(doc_version);
-
-                document.title = payload.title;
-                document.description = payload.description;
-                document.file_url = payload.file_url;
-                document.version = new_version;
-                document.updated_at = Some(time());
-
-                do_insert_document(&document);
-                Ok(document)
+    audit::audited(AuditOperation::UpdateDocument, Some(id), || {
+        authenticate()?;
+        let document = load_document(id)?;
+        if document.is_deleted {
+            return Err(Error::DocumentDeleted);
+        }
+        authorize(&document, Role::Editor)?;
+        locks::ensure_not_locked_by_other(id)?;
+        holds::ensure_not_held(id)?;
+        match payload.expected_version {
+            None => {
+                return Err(Error::InvalidArgument {
+                    msg: "expected_version is required to update a document".to_string(),
+                })
             }
-            None => Err(Error::NotFound { msg: format!("Document with id {} not found", id) }),
+            Some(expected) if expected != document.version => {
+                return Err(Error::VersionConflict { current: document.version })
+            }
+            Some(_) => {}
         }
+        apply_update(document, payload)
     })
 }
 
+// Record the payload as the document's next version
+fn apply_update(mut document: Document, payload: DocumentPayload) -> Result<Document, Error> {
+    let id = document.id;
+    validate_payload(&payload, "")?;
+    if let Some(content_id) = payload.content_id {
+        content::attach_content(content_id, id)?;
+    }
+
+    let new_version = document.version + 1;
+    let doc_version = DocumentVersion {
+        version: new_version,
+        title: payload.title.clone(),
+        description: payload.description.clone(),
+        file_url: payload.file_url.clone(),
+        content_id: payload.content_id,
+        metadata: payload.metadata.clone(),
+        updated_by: caller(),
+        updated_at: time(),
+        lifecycle: Some(lifecycle::draft_record()),
+        previous_hash: ByteBuf::new(),
+        hash: ByteBuf::new(),
+    };
+    append_version(&mut document, doc_version);
+
+    search::unindex_document(&document);
+    document.title = payload.title;
+    document.description = payload.description;
+    document.file_url = payload.file_url;
+    document.content_id = payload.content_id;
+    document.version = new_version;
+    document.updated_at = Some(time());
+    document.updated_by = Some(caller());
+
+    do_insert_document(&document);
+    search::index_document(&document);
+    lifecycle::reset_to_draft(id);
+    envelopes::void_superseded(id, document.version);
+    log_version_block(icrc3::BTYPE_UPDATE, &document, Vec::new());
+    changes::record(id, ChangeKind::Updated, document.version, None);
+    Ok(document)
+}
+
 // Soft delete document, can be restored later
 #[ic_cdk::update]
-fn soft_delete_document(id: u64) -> Result<Document, Error> {
-    STORAGE.with(|service| {
-        let mut storage = service.borrow_mut();
-        
-        if let Some(mut document) = storage.remove(&id) {
-            if document.is_deleted {
-                // If already deleted, return an error
-                storage.insert(id, document); // Reinserting the document back if no update is made
-                return Err(Error::AlreadyDeleted);
-            }
-            
-            // Mark the document as deleted and reinsert it
-            document.is_deleted = true;
-            storage.insert(id, document.clone());
-            Ok(document)
-        } else {
-            // Document not found
-            Err(Error::NotFound { msg: format!("Document with id {} not found", id) })
+fn soft_delete_document(id: u64, reason: Option<String>) -> Result<Document, Error> {
+    audit::audited(AuditOperation::SoftDeleteDocument, Some(id), || {
+        authenticate()?;
+        let mut document = load_document(id)?;
+        authorize(&document, Role::Owner)?;
+        if document.is_deleted {
+            return Err(Error::AlreadyDeleted);
         }
+        locks::ensure_not_locked_by_other(id)?;
+        holds::ensure_not_held(id)?;
+        if reason.as_ref().is_some_and(|reason| reason.len() > MAX_REASON_LEN) {
+            return Err(Error::PayloadTooLarge {
+                field: "reason".to_string(),
+                limit: MAX_REASON_LEN as u64,
+            });
+        }
+
+        // Mark the document as deleted and reinsert it
+        document.is_deleted = true;
+        document.deletion =
+            Some(DeletionRecord { deleted_by: caller(), deleted_at: time(), reason });
+        do_insert_document(&document);
+        search::unindex_document(&document);
+        record_event(id, DocumentEventKind::Deleted);
+        let reason = document.deletion.as_ref().and_then(|deletion| deletion.reason.as_deref());
+        let fields = reason.map(|reason| ("reason", Value::text(reason))).into_iter().collect();
+        icrc3::log_document_block(icrc3::BTYPE_DELETE, id, fields);
+        changes::record(id, ChangeKind::Deleted, document.version, None);
+        Ok(document)
     })
 }
 
 // Restore a soft-deleted document
 #[ic_cdk::update]
 fn restore_document(id: u64) -> Result<Document, Error> {
-    STORAGE.with(|service| {
-        let mut storage = service.borrow_mut();
-        
-        if let Some(mut document) = storage.remove(&id) {
-            if !document.is_deleted {
-                // If not deleted, return an error
-                storage.insert(id, document); // Reinserting the document back if no update is made
-                return Err(Error::NotDeleted);
-            }
-            
-            // Mark the document as restored and reinsert it
-            document.is_deleted = false;
-            storage.insert(id, document.clone());
-            Ok(document)
-        } else {
-            // Document not found
-            Err(Error::NotFound { msg: format!("Document with id {} not found", id) })
+    audit::audited(AuditOperation::RestoreDocument, Some(id), || {
+        authenticate()?;
+        let mut document = load_document(id)?;
+        authorize(&document, Role::Owner)?;
+        if !document.is_deleted {
+            return Err(Error::NotDeleted);
         }
+
+        // Mark the document as restored and reinsert it
+        document.is_deleted = false;
+        document.deletion = None;
+        do_insert_document(&document);
+        search::index_document(&document);
+        record_event(id, DocumentEventKind::Restored);
+        icrc3::log_document_block(icrc3::BTYPE_RESTORE, id, Vec::new());
+        changes::record(id, ChangeKind::Restored, document.version, None);
+        Ok(document)
     })
 }
 
-// Search for documents by title or description
+// Retrieve a document by ID, with a certificate and witness proving its contents
+#[ic_cdk::query]
+fn get_document(id: u64) -> Result<CertifiedDocument, Error> {
+    readable_document(id).map(certification::certified_document)
+}
+
+// Load a live document the caller is allowed to read
+fn readable_document(id: u64) -> Result<Document, Error> {
+    let document = load_document(id)?;
+    authorize(&document, Role::Reader)?;
+    if document.is_deleted {
+        return Err(Error::DocumentDeleted);
+    }
+    Ok(document)
+}
+
+// List the create/delete/restore events of a document, oldest first
 #[ic_cdk::query]
-fn search_documents(query: String) -> Vec<Document> {
-    STORAGE.with(|service| {
-        let all_docs: Vec<Document> = service.borrow().iter().map(|(_, doc)| doc.clone()).collect();
-        all_docs.into_iter().filter(|doc| {
-            doc.title.to_lowercase().contains(&query.to_lowercase()) ||
-            doc.description.to_lowercase().contains(&query.to_lowercase())
-        }).collect()
+fn list_document_events(id: u64) -> Result<Vec<DocumentEvent>, Error> {
+    let document = load_document(id)?;
+    authorize(&document, Role::Reader)?;
+
+    Ok(EVENTS.with(|events| {
+        events
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, event)| event)
+            .collect()
+    }))
+}
+
+// Grant a principal a role on a document, replacing any role it already holds
+#[ic_cdk::update]
+fn grant_permission(id: u64, principal: Principal, role: Role) -> Result<Vec<Permission>, Error> {
+    audit::audited(AuditOperation::GrantPermission, Some(id), || {
+        authenticate()?;
+        let document = load_document(id)?;
+        if document.is_deleted {
+            return Err(Error::DocumentDeleted);
+        }
+        authorize(&document, Role::Owner)?;
+        if principal == document.owner {
+            return Err(Error::InvalidArgument {
+                msg: "The document owner's role cannot be changed".to_string(),
+            });
+        }
+
+        PERMISSIONS.with(|acl| acl.borrow_mut().insert((id, principal_key(&principal)), role));
+        icrc3::log_document_block(
+            icrc3::BTYPE_GRANT,
+            id,
+            vec![
+                ("principal", Value::blob(principal.as_slice())),
+                ("role", Value::text(role.name())),
+            ],
+        );
+        changes::record(id, ChangeKind::AccessGranted, document.version, Some(principal));
+        Ok(document_permissions(&document))
     })
 }
 
-// Retrieve a document by ID
+// Revoke every role a principal holds on a document
+#[ic_cdk::update]
+fn revoke_permission(id: u64, principal: Principal) -> Result<Vec<Permission>, Error> {
+    audit::audited(AuditOperation::RevokePermission, Some(id), || {
+        authenticate()?;
+        let document = load_document(id)?;
+        if document.is_deleted {
+            return Err(Error::DocumentDeleted);
+        }
+        authorize(&document, Role::Owner)?;
+        if principal == document.owner {
+            return Err(Error::InvalidArgument {
+                msg: "The document owner cannot be revoked".to_string(),
+            });
+        }
+
+        PERMISSIONS
+            .with(|acl| acl.borrow_mut().remove(&(id, principal_key(&principal))))
+            .ok_or_else(|| Error::NotFound {
+                msg: format!("Principal {} has no permission on document {}", principal, id),
+            })?;
+        let fields = vec![("principal", Value::blob(principal.as_slice()))];
+        icrc3::log_document_block(icrc3::BTYPE_REVOKE, id, fields);
+        changes::record(id, ChangeKind::AccessRevoked, document.version, Some(principal));
+        Ok(document_permissions(&document))
+    })
+}
+
+// List the access control entries of a document, including its owner
 #[ic_cdk::query]
-fn get_document(id: u64) -> Result<Document, Error> {
-    STORAGE.with(|s| match s.borrow().get(&id) {
-        Some(document) if !document.is_deleted => Ok(document.clone()),
-        Some(_) => Err(Error::DocumentDeleted),
-        None => Err(Error::NotFound { msg: format!("Document with id {} not found", id) }),
+fn list_permissions(id: u64) -> Result<Vec<Permission>, Error> {
+    let document = readable_document(id)?;
+    Ok(document_permissions(&document))
+}
+
+fn load_document(id: u64) -> Result<Document, Error> {
+    STORAGE
+        .with(|service| service.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound { msg: format!("Document with id {} not found", id) })?
+        .decode(id)
+}
+
+fn principal_key(principal: &Principal) -> PrincipalKey {
+    PrincipalKey::try_from(principal.as_slice()).expect("principal exceeds 29 bytes")
+}
+
+// Role the principal holds on the document; the owner always holds Role::Owner
+fn role_of(document: &Document, principal: &Principal) -> Option<Role> {
+    if *principal == document.owner {
+        return Some(Role::Owner);
+    }
+    PERMISSIONS.with(|acl| acl.borrow().get(&(document.id, principal_key(principal))))
+}
+
+fn has_role(document: &Document, principal: &Principal, required: Role) -> bool {
+    role_of(document, principal).is_some_and(|role| role >= required)
+}
+
+// Ensure the caller holds at least `required` on the document
+fn authorize(document: &Document, required: Role) -> Result<(), Error> {
+    let principal = caller();
+    if has_role(document, &principal, required) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!(
+                "Principal {} is not allowed to access document {}",
+                principal, document.id
+            ),
+        })
+    }
+}
+
+// Canister controllers act as admins
+fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+// Anonymous callers can read what is shared with everyone but cannot change anything
+fn authenticate() -> Result<(), Error> {
+    if caller() == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot modify documents".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn authorize_admin() -> Result<(), Error> {
+    let principal = caller();
+    if is_admin(&principal) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized { msg: format!("Principal {} is not an admin", principal) })
+    }
+}
+
+fn document_permissions(document: &Document) -> Vec<Permission> {
+    let mut permissions = vec![Permission { principal: document.owner, role: Role::Owner }];
+    PERMISSIONS.with(|acl| {
+        let lower = (document.id, PrincipalKey::default());
+        permissions.extend(
+            acl.borrow()
+                .range(lower..)
+                .take_while(|((id, _), _)| *id == document.id)
+                .map(|((_, key), role)| Permission {
+                    principal: Principal::from_slice(key.as_slice()),
+                    role,
+                }),
+        );
+    });
+    permissions
+}
+
+// Page through a document's version history, oldest first, starting at `from_version`
+#[ic_cdk::query]
+fn list_document_versions(id: u64, from_version: u64, limit: u64) -> Result<VersionPage, Error> {
+    readable_document(id)?;
+
+    let limit = limit.clamp(1, MAX_VERSION_PAGE_SIZE) as usize;
+    HISTORY.with(|history| {
+        let mut versions = history
+            .borrow()
+            .range((id, from_version)..=(id, u64::MAX))
+            .take(limit + 1)
+            .map(|(_, stored)| stored.decode(id))
+            .collect::<Result<Vec<DocumentVersion>, Error>>()?;
+
+        let next_version = if versions.len() > limit {
+            versions.pop().map(|version| version.version)
+        } else {
+            None
+        };
+
+        Ok(VersionPage { versions, next_version })
     })
 }
 
+// Retrieve a single version of a document
+#[ic_cdk::query]
+fn get_document_version(id: u64, version: u64) -> Result<DocumentVersion, Error> {
+    readable_document(id)?;
+    load_version(id, version)
+}
+
+// Compare two versions of a document field by field and line by line
+#[ic_cdk::query]
+fn diff_versions(id: u64, from: u64, to: u64) -> Result<VersionDiff, Error> {
+    readable_document(id)?;
+    let from = load_version(id, from)?;
+    let to = load_version(id, to)?;
+    Ok(diff::diff_versions(id, &from, &to))
+}
+
+// Restore the content of an earlier version as a new version; history is never rewritten
+#[ic_cdk::update]
+fn revert_document(id: u64, version: u64) -> Result<Document, Error> {
+    audit::audited(AuditOperation::RevertDocument, Some(id), || {
+        authenticate()?;
+        let document = load_document(id)?;
+        if document.is_deleted {
+            return Err(Error::DocumentDeleted);
+        }
+        authorize(&document, Role::Editor)?;
+        locks::ensure_not_locked_by_other(id)?;
+        holds::ensure_not_held(id)?;
+        let target = load_version(id, version)?;
+
+        apply_update(
+            document,
+            DocumentPayload {
+                title: target.title,
+                description: target.description,
+                file_url: target.file_url,
+                content_id: target.content_id,
+                metadata: DocumentMetadata {
+                    display_name: None,
+                    change_summary: format!("Reverted to version {}", version),
+                },
+                expected_version: None,
+            },
+        )
+    })
+}
+
+fn load_version(id: u64, version: u64) -> Result<DocumentVersion, Error> {
+    HISTORY
+        .with(|history| history.borrow().get(&(id, version)))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Version {} of document {} not found", version, id),
+        })?
+        .decode(id)
+}
+
 #[derive(candid::CandidType, Deserialize, Serialize)]
 enum Error {
     NotFound { msg: String },
     DocumentDeleted,
     AlreadyDeleted,
     NotDeleted,
+    Unauthorized { msg: String },
+    InvalidArgument { msg: String },
+    // The document moved on from the version the client edited
+    VersionConflict { current: u64 },
+    // The document is checked out by another principal
+    Locked { holder: Principal, expires_at: u64 },
+    // The management canister could not produce a threshold signature
+    SigningFailed { msg: String },
+    // The document is frozen by an active legal hold
+    UnderLegalHold { hold_id: u64, case_reference: String },
+    // The document's lifecycle state does not allow the requested transition
+    InvalidTransition { from: LifecycleState, to: LifecycleState },
+    // The caller used up a quota
+    QuotaExceeded { msg: String },
+    // A payload field is longer than its limit, in bytes
+    PayloadTooLarge { field: String, limit: u64 },
+    // The stored document cannot be decoded
+    CorruptRecord { id: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound { msg } => write!(f, "Not found: {}", msg),
+            Error::DocumentDeleted => write!(f, "Document is deleted"),
+            Error::AlreadyDeleted => write!(f, "Document is already deleted"),
+            Error::NotDeleted => write!(f, "Document is not deleted"),
+            Error::Unauthorized { msg } => write!(f, "Unauthorized: {}", msg),
+            Error::InvalidArgument { msg } => write!(f, "Invalid argument: {}", msg),
+            Error::VersionConflict { current } => {
+                write!(f, "Version conflict: the document is at version {}", current)
+            }
+            Error::Locked { holder, expires_at } => {
+                write!(f, "Document is checked out by {} until {}", holder, expires_at)
+            }
+            Error::SigningFailed { msg } => write!(f, "Signing failed: {}", msg),
+            Error::UnderLegalHold { hold_id, case_reference } => {
+                write!(f, "Document is under legal hold {} ({})", hold_id, case_reference)
+            }
+            Error::InvalidTransition { from, to } => {
+                write!(f, "Cannot move the document from {} to {}", from.name(), to.name())
+            }
+            Error::QuotaExceeded { msg } => write!(f, "Quota exceeded: {}", msg),
+            Error::PayloadTooLarge { field, limit } => {
+                write!(f, "Payload too large: {} exceeds {} bytes", field, limit)
+            }
+            Error::CorruptRecord { id } => write!(f, "Document {} cannot be decoded", id),
+        }
+    }
+}
+
+// Live document owned by the anonymous principal, created at time `id`; for unit tests
+#[cfg(test)]
+fn test_document(id: u64, title: &str, description: &str, version: u64) -> Document {
+    Document {
+        id,
+        owner: Principal::anonymous(),
+        title: title.to_string(),
+        description: description.to_string(),
+        file_url: String::new(),
+        content_id: None,
+        version,
+        created_at: id,
+        updated_at: None,
+        updated_by: None,
+        is_deleted: false,
+        deletion: None,
+        history_head: ByteBuf::new(),
+    }
 }
 
 ic_cdk::export_candid!();
@@ -0,0 +1,377 @@
+// Draft / review / approval / publication lifecycle of documents.
+//
+// Every document starts as a Draft. A principal holding the policy's submit role
+// sends it for review, the policy's reviewers approve it (N of M) or reject it back
+// to Draft, and once Approved a principal holding the publish role publishes it.
+// Each transition is recorded as a new version carrying the new state and, for
+// approvals, the reviewers who approved, so the history proves what was approved and
+// by whom. Editing the document sends it back to Draft and discards pending approvals.
+use crate::audit::{self, AuditOperation};
+use crate::changes::{self, ChangeKind};
+use crate::icrc3::{self, Value};
+use crate::{
+    append_version, authenticate, authorize, do_insert_document, envelopes, has_role, holds,
+    load_document, locks, log_version_block, Document, DocumentMetadata, DocumentVersion, Error,
+    Memory, Role, MAX_REASON_LEN, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::{borrow::Cow, cell::RefCell};
+
+// Maximum number of reviewers named by a policy
+pub(crate) const MAX_REVIEWERS: usize = 10;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub(crate) enum LifecycleState {
+    #[default]
+    Draft,
+    InReview,
+    Approved,
+    Published,
+}
+
+impl LifecycleState {
+    // Stable name of the state, as hashed into version history
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            LifecycleState::Draft => "Draft",
+            LifecycleState::InReview => "InReview",
+            LifecycleState::Approved => "Approved",
+            LifecycleState::Published => "Published",
+        }
+    }
+}
+
+// Lifecycle state recorded on a version
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct LifecycleRecord {
+    pub(crate) state: LifecycleState,
+    // Reviewers whose approvals moved the document to Approved
+    pub(crate) approved_by: Vec<Principal>,
+}
+
+// Who may move a document through its lifecycle
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LifecyclePolicy {
+    // Principals allowed to approve or reject; when empty, the document's owners
+    reviewers: Vec<Principal>,
+    // Approvals needed before the document is Approved
+    required_approvals: u32,
+    // Role needed to send the document for review
+    submit_role: Role,
+    // Role needed to publish an approved document
+    publish_role: Role,
+}
+
+impl Default for LifecyclePolicy {
+    fn default() -> Self {
+        Self {
+            reviewers: Vec::new(),
+            required_approvals: 1,
+            submit_role: Role::Editor,
+            publish_role: Role::Owner,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Approval {
+    reviewer: Principal,
+    approved_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DocumentLifecycle {
+    document_id: u64,
+    state: LifecycleState,
+    policy: LifecyclePolicy,
+    // Approvals collected during the current review
+    approvals: Vec<Approval>,
+}
+
+// Storable trait for DocumentLifecycle
+impl Storable for DocumentLifecycle {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for DocumentLifecycle
+impl BoundedStorable for DocumentLifecycle {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Lifecycle of documents that left the default Draft state or have their own policy
+    static LIFECYCLES: RefCell<StableBTreeMap<u64, DocumentLifecycle, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+}
+
+// Current state, policy and pending approvals of a document
+#[ic_cdk::query]
+fn get_lifecycle(id: u64) -> Result<DocumentLifecycle, Error> {
+    let document = load_document(id)?;
+    authorize(&document, Role::Reader)?;
+    Ok(load_lifecycle(id))
+}
+
+// Replace the lifecycle policy of a document; owners only, and not during a review
+#[ic_cdk::update]
+fn set_lifecycle_policy(id: u64, policy: LifecyclePolicy) -> Result<DocumentLifecycle, Error> {
+    audit::audited(AuditOperation::SetLifecyclePolicy, Some(id), || {
+        authenticate()?;
+        let document = live_document(id)?;
+        authorize(&document, Role::Owner)?;
+
+        let mut lifecycle = load_lifecycle(id);
+        if lifecycle.state == LifecycleState::InReview {
+            return Err(Error::InvalidArgument {
+                msg: "The policy cannot change while the document is in review".to_string(),
+            });
+        }
+        if policy.reviewers.len() > MAX_REVIEWERS {
+            return Err(Error::InvalidArgument {
+                msg: format!("A policy can name at most {} reviewers", MAX_REVIEWERS),
+            });
+        }
+        for (position, reviewer) in policy.reviewers.iter().enumerate() {
+            if policy.reviewers[..position].contains(reviewer) {
+                return Err(Error::InvalidArgument { msg: format!("{} is listed twice", reviewer) });
+            }
+            if !has_role(&document, reviewer, Role::Reader) {
+                return Err(Error::InvalidArgument {
+                    msg: format!("{} cannot read document {}", reviewer, id),
+                });
+            }
+        }
+        let reviewers = policy.reviewers.len() as u32;
+        let required = policy.required_approvals;
+        if required == 0 || (reviewers > 0 && required > reviewers) {
+            return Err(Error::InvalidArgument {
+                msg: "Required approvals must be between 1 and the number of reviewers".to_string(),
+            });
+        }
+
+        lifecycle.policy = policy;
+        save(&lifecycle);
+        Ok(lifecycle)
+    })
+}
+
+// Send a draft for review
+#[ic_cdk::update]
+fn submit_for_review(id: u64) -> Result<DocumentLifecycle, Error> {
+    audit::audited(AuditOperation::SubmitForReview, Some(id), || {
+        authenticate()?;
+        let mut document = live_document(id)?;
+        let mut lifecycle = load_lifecycle(id);
+        authorize(&document, lifecycle.policy.submit_role)?;
+        locks::ensure_not_locked_by_other(id)?;
+        holds::ensure_not_held(id)?;
+        expect_state(&lifecycle, LifecycleState::Draft, LifecycleState::InReview)?;
+
+        lifecycle.approvals.clear();
+        let summary = "Submitted for review".to_string();
+        transition(&mut document, &mut lifecycle, LifecycleState::InReview, Vec::new(), summary);
+        Ok(lifecycle)
+    })
+}
+
+// Approve a document in review; the document becomes Approved once enough reviewers
+// have approved it
+#[ic_cdk::update]
+fn approve_document(id: u64) -> Result<DocumentLifecycle, Error> {
+    audit::audited(AuditOperation::ApproveDocument, Some(id), || {
+        authenticate()?;
+        let mut document = live_document(id)?;
+        let mut lifecycle = load_lifecycle(id);
+        authorize_reviewer(&document, &lifecycle)?;
+        locks::ensure_not_locked_by_other(id)?;
+        holds::ensure_not_held(id)?;
+        expect_state(&lifecycle, LifecycleState::InReview, LifecycleState::Approved)?;
+
+        let reviewer = caller();
+        if lifecycle.approvals.iter().any(|approval| approval.reviewer == reviewer) {
+            return Err(Error::InvalidArgument {
+                msg: format!("{} has already approved document {}", reviewer, id),
+            });
+        }
+        lifecycle.approvals.push(Approval { reviewer, approved_at: time() });
+
+        let approvals = lifecycle.approvals.len();
+        if approvals >= lifecycle.policy.required_approvals as usize {
+            let approved_by =
+                lifecycle.approvals.iter().map(|approval| approval.reviewer).collect();
+            let summary = format!("Approved by {} reviewer(s)", approvals);
+            let state = LifecycleState::Approved;
+            transition(&mut document, &mut lifecycle, state, approved_by, summary);
+        } else {
+            save(&lifecycle);
+        }
+        Ok(lifecycle)
+    })
+}
+
+// Send a document in review back to Draft
+#[ic_cdk::update]
+fn reject_document(id: u64, reason: String) -> Result<DocumentLifecycle, Error> {
+    audit::audited(AuditOperation::RejectDocument, Some(id), || {
+        authenticate()?;
+        let mut document = live_document(id)?;
+        let mut lifecycle = load_lifecycle(id);
+        authorize_reviewer(&document, &lifecycle)?;
+        locks::ensure_not_locked_by_other(id)?;
+        holds::ensure_not_held(id)?;
+        expect_state(&lifecycle, LifecycleState::InReview, LifecycleState::Draft)?;
+        if reason.trim().is_empty() {
+            return Err(Error::InvalidArgument { msg: "Reason must not be empty".to_string() });
+        }
+        if reason.len() > MAX_REASON_LEN {
+            return Err(Error::PayloadTooLarge {
+                field: "reason".to_string(),
+                limit: MAX_REASON_LEN as u64,
+            });
+        }
+
+        lifecycle.approvals.clear();
+        let summary = format!("Review rejected: {}", reason);
+        transition(&mut document, &mut lifecycle, LifecycleState::Draft, Vec::new(), summary);
+        Ok(lifecycle)
+    })
+}
+
+// Publish an approved document
+#[ic_cdk::update]
+fn publish_document(id: u64) -> Result<DocumentLifecycle, Error> {
+    audit::audited(AuditOperation::PublishDocument, Some(id), || {
+        authenticate()?;
+        let mut document = live_document(id)?;
+        let mut lifecycle = load_lifecycle(id);
+        authorize(&document, lifecycle.policy.publish_role)?;
+        locks::ensure_not_locked_by_other(id)?;
+        holds::ensure_not_held(id)?;
+        expect_state(&lifecycle, LifecycleState::Approved, LifecycleState::Published)?;
+
+        let summary = "Published".to_string();
+        transition(&mut document, &mut lifecycle, LifecycleState::Published, Vec::new(), summary);
+        Ok(lifecycle)
+    })
+}
+
+// Lifecycle recorded on versions created by edits
+pub(crate) fn draft_record() -> LifecycleRecord {
+    LifecycleRecord::default()
+}
+
+// Send an edited document back to Draft, discarding any pending approvals
+pub(crate) fn reset_to_draft(id: u64) {
+    let mut lifecycle = load_lifecycle(id);
+    if lifecycle.state != LifecycleState::Draft || !lifecycle.approvals.is_empty() {
+        lifecycle.state = LifecycleState::Draft;
+        lifecycle.approvals.clear();
+        save(&lifecycle);
+    }
+}
+
+// Drop the lifecycle of a document that no longer exists
+pub(crate) fn remove_lifecycle(id: u64) {
+    LIFECYCLES.with(|lifecycles| lifecycles.borrow_mut().remove(&id));
+}
+
+fn load_lifecycle(id: u64) -> DocumentLifecycle {
+    LIFECYCLES
+        .with(|lifecycles| lifecycles.borrow().get(&id))
+        .unwrap_or_else(|| DocumentLifecycle {
+            document_id: id,
+            state: LifecycleState::Draft,
+            policy: LifecyclePolicy::default(),
+            approvals: Vec::new(),
+        })
+}
+
+fn save(lifecycle: &DocumentLifecycle) {
+    LIFECYCLES.with(|lifecycles| {
+        lifecycles.borrow_mut().insert(lifecycle.document_id, lifecycle.clone())
+    });
+}
+
+fn live_document(id: u64) -> Result<Document, Error> {
+    let document = load_document(id)?;
+    if document.is_deleted {
+        return Err(Error::DocumentDeleted);
+    }
+    Ok(document)
+}
+
+fn expect_state(
+    lifecycle: &DocumentLifecycle,
+    from: LifecycleState,
+    to: LifecycleState,
+) -> Result<(), Error> {
+    if lifecycle.state != from {
+        return Err(Error::InvalidTransition { from: lifecycle.state, to });
+    }
+    Ok(())
+}
+
+// Reviewers named by the policy, or the document's owners when it names none
+fn authorize_reviewer(document: &Document, lifecycle: &DocumentLifecycle) -> Result<(), Error> {
+    if lifecycle.policy.reviewers.is_empty() {
+        return authorize(document, Role::Owner);
+    }
+    if !lifecycle.policy.reviewers.contains(&caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!("Caller is not a reviewer of document {}", document.id),
+        });
+    }
+    Ok(())
+}
+
+// Move the document to `state`, recording the transition as a new version with the
+// current content
+fn transition(
+    document: &mut Document,
+    lifecycle: &mut DocumentLifecycle,
+    state: LifecycleState,
+    approved_by: Vec<Principal>,
+    change_summary: String,
+) {
+    let new_version = document.version + 1;
+    append_version(
+        document,
+        DocumentVersion {
+            version: new_version,
+            title: document.title.clone(),
+            description: document.description.clone(),
+            file_url: document.file_url.clone(),
+            content_id: document.content_id,
+            metadata: DocumentMetadata { display_name: None, change_summary },
+            updated_by: caller(),
+            updated_at: time(),
+            lifecycle: Some(LifecycleRecord { state, approved_by }),
+            previous_hash: ByteBuf::new(),
+            hash: ByteBuf::new(),
+        },
+    );
+    document.version = new_version;
+    document.updated_at = Some(time());
+    document.updated_by = Some(caller());
+    do_insert_document(document);
+    envelopes::void_superseded(document.id, document.version);
+    let fields = vec![("state", Value::text(state.name()))];
+    log_version_block(icrc3::BTYPE_UPDATE, document, fields);
+    changes::record(document.id, ChangeKind::Updated, document.version, None);
+
+    lifecycle.state = state;
+    save(lifecycle);
+}
@@ -0,0 +1,426 @@
+// Paginated, filterable and sortable listing of the documents a caller can see.
+//
+// Pages are addressed by a cursor holding the sort key and id of the last document
+// of the previous page, so a listing stays stable while documents are added. Listings
+// by creation time follow the ids of the store; the other orders are kept in a stable
+// index of (sort field, sort key, id) entries that is updated with every write, so a
+// page only walks the entries after its cursor.
+use crate::{has_role, load_document, Document, Memory, Role, MEMORY_MANAGER, STORAGE};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::ops::Bound;
+use std::{borrow::Cow, cell::RefCell};
+
+// Maximum number of documents returned by a single listing call
+const MAX_LIST_PAGE_SIZE: u32 = 100;
+
+// Maximum number of documents a single listing call looks at; documents the caller
+// cannot see still count, so a page may come back short with a `next_cursor`
+const MAX_LIST_SCAN: usize = 10_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub(crate) enum SortField {
+    #[default]
+    CreatedAt,
+    // Time of the last update, or creation time for documents never updated
+    UpdatedAt,
+    Title,
+    Version,
+    // Time of the soft deletion; documents that are not deleted sort first
+    DeletedAt,
+}
+
+// Sort fields kept in SORT_INDEX
+const INDEXED_FIELDS: [SortField; 4] =
+    [SortField::UpdatedAt, SortField::Title, SortField::Version, SortField::DeletedAt];
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub(crate) enum SortDirection {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+// Which documents to include with respect to soft deletion
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub(crate) enum DeletionFilter {
+    #[default]
+    Active,
+    Deleted,
+    Any,
+}
+
+// Criteria a document must meet to be listed; bounds are inclusive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ListFilter {
+    pub(crate) created_after: Option<u64>,
+    pub(crate) created_before: Option<u64>,
+    pub(crate) updated_after: Option<u64>,
+    pub(crate) updated_before: Option<u64>,
+    pub(crate) deletion: DeletionFilter,
+    pub(crate) owner: Option<Principal>,
+}
+
+// Value a listing is ordered by
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum SortKey {
+    Number(u64),
+    Text(String),
+}
+
+// Position after which the next page starts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ListCursor {
+    key: SortKey,
+    id: u64,
+}
+
+// Position of a document in the order of one sort field
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct IndexEntry {
+    // Discriminant of the SortField; the variants must keep their order since it is stored
+    field: u8,
+    key: SortKey,
+    id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ListRequest {
+    cursor: Option<ListCursor>,
+    page_size: u32,
+    sort_by: SortField,
+    direction: SortDirection,
+    filter: ListFilter,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DocumentPage {
+    documents: Vec<Document>,
+    // Cursor for the following page, absent on the last page
+    next_cursor: Option<ListCursor>,
+}
+
+// Storable trait for IndexEntry
+impl Storable for IndexEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for IndexEntry; titles are at most MAX_TITLE_LEN bytes, which
+// lowercasing can grow by half
+impl BoundedStorable for IndexEntry {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Listing order of every document for each of INDEXED_FIELDS
+    static SORT_INDEX: RefCell<StableBTreeMap<IndexEntry, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+}
+
+// List the documents the caller can read, one page at a time
+#[ic_cdk::query]
+fn list_documents(request: ListRequest) -> DocumentPage {
+    list(request)
+}
+
+// List the soft-deleted documents the caller owns, most recently deleted first
+#[ic_cdk::query]
+fn list_trash(cursor: Option<ListCursor>, page_size: u32) -> DocumentPage {
+    list(ListRequest {
+        cursor,
+        page_size,
+        sort_by: SortField::DeletedAt,
+        direction: SortDirection::Descending,
+        filter: ListFilter { deletion: DeletionFilter::Deleted, ..Default::default() },
+    })
+}
+
+fn list(request: ListRequest) -> DocumentPage {
+    let principal = caller();
+    let page_size = request.page_size.clamp(1, MAX_LIST_PAGE_SIZE) as usize;
+    let sort_by = request.sort_by;
+
+    let mut documents = Vec::new();
+    let mut scanned = 0;
+    let mut last_scanned = None;
+    let mut scan_cut_short = false;
+    walk(sort_by, request.cursor.as_ref(), request.direction, |key, id| {
+        if scanned == MAX_LIST_SCAN {
+            scan_cut_short = true;
+            return false;
+        }
+        scanned += 1;
+        if let Ok(document) = load_document(id) {
+            // An entry left behind by a record that could not be decoded when it was
+            // overwritten no longer matches the document
+            let current = key.as_ref().is_none_or(|key| *key == sort_key(&document, sort_by));
+            if current
+                && matches_filter(&document, &request.filter)
+                && can_list(&document, &principal)
+            {
+                documents.push(document);
+            }
+        }
+        // Listings in id order only read the id of the cursor
+        last_scanned = Some(ListCursor { key: key.unwrap_or(SortKey::Number(0)), id });
+        documents.len() <= page_size
+    });
+
+    let next_cursor = if documents.len() > page_size {
+        documents.truncate(page_size);
+        documents.last().map(|document| ListCursor {
+            key: sort_key(document, sort_by),
+            id: document.id,
+        })
+    } else if scan_cut_short {
+        last_scanned
+    } else {
+        None
+    };
+    DocumentPage { documents, next_cursor }
+}
+
+// Add a document to the sort index
+pub(crate) fn index_document(document: &Document) {
+    SORT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for field in INDEXED_FIELDS {
+            index.insert(index_entry(document, field), ());
+        }
+    });
+}
+
+// Remove a document from the sort index, as it was when indexed
+pub(crate) fn unindex_document(document: &Document) {
+    SORT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for field in INDEXED_FIELDS {
+            index.remove(&index_entry(document, field));
+        }
+    });
+}
+
+// Index every stored document; run by the schema migration that introduced the index
+pub(crate) fn rebuild_index() {
+    let ids: Vec<u64> = STORAGE.with(|service| service.borrow().iter().map(|(id, _)| id).collect());
+    for id in ids {
+        if let Ok(document) = load_document(id) {
+            index_document(&document);
+        }
+    }
+}
+
+// Call `visit` with the id of each document soft-deleted at or before `cutoff`, oldest
+// deletion first, until it returns false
+pub(crate) fn walk_deleted_before(cutoff: u64, mut visit: impl FnMut(u64) -> bool) {
+    let field = SortField::DeletedAt as u8;
+    // Documents that are not deleted are indexed under 0
+    let start = IndexEntry { field, key: SortKey::Number(0), id: u64::MAX };
+    let end = IndexEntry { field, key: SortKey::Number(cutoff), id: u64::MAX };
+    SORT_INDEX.with(|index| {
+        for (entry, _) in index.borrow().range((Bound::Excluded(start), Bound::Included(end))) {
+            if !visit(entry.id) {
+                break;
+            }
+        }
+    });
+}
+
+fn index_entry(document: &Document, field: SortField) -> IndexEntry {
+    IndexEntry { field: field as u8, key: sort_key(document, field), id: document.id }
+}
+
+// Call `visit` with the sort key and id of each document after `cursor` in listing
+// order, until it returns false. The key is None when listing in id order, which
+// does not need it.
+fn walk(
+    sort_by: SortField,
+    cursor: Option<&ListCursor>,
+    direction: SortDirection,
+    mut visit: impl FnMut(Option<SortKey>, u64) -> bool,
+) {
+    if sort_by == SortField::CreatedAt {
+        let after = cursor.map(|cursor| cursor.id);
+        return walk_ids(after, direction, |id| visit(None, id));
+    }
+
+    let field = sort_by as u8;
+    let first = IndexEntry { field, key: SortKey::Number(0), id: 0 };
+    // Number keys sort before text keys, so this is past every entry of the field
+    let past_last = IndexEntry { field: field + 1, key: SortKey::Number(0), id: 0 };
+    let after = cursor.map(|cursor| IndexEntry { field, key: cursor.key.clone(), id: cursor.id });
+    SORT_INDEX.with(|index| {
+        let index = index.borrow();
+        match direction {
+            SortDirection::Ascending => {
+                let start = after.map_or(Bound::Included(first), Bound::Excluded);
+                for (entry, _) in index.range((start, Bound::Excluded(past_last))) {
+                    if !visit(Some(entry.key), entry.id) {
+                        break;
+                    }
+                }
+            }
+            SortDirection::Descending => {
+                // The map only iterates forwards, so step below the previous entry each time
+                let mut bound = after.unwrap_or(past_last);
+                while let Some((entry, _)) = index.iter_upper_bound(&bound).next() {
+                    if entry.field != field || !visit(Some(entry.key.clone()), entry.id) {
+                        break;
+                    }
+                    bound = entry;
+                }
+            }
+        }
+    });
+}
+
+// Call `visit` with each id of the store after `after`, in `direction`, until it
+// returns false
+fn walk_ids(after: Option<u64>, direction: SortDirection, mut visit: impl FnMut(u64) -> bool) {
+    STORAGE.with(|service| {
+        let storage = service.borrow();
+        match direction {
+            SortDirection::Ascending => {
+                if after == Some(u64::MAX) {
+                    return;
+                }
+                let start = after.map_or(0, |id| id + 1);
+                for (id, _) in storage.range(start..) {
+                    if !visit(id) {
+                        break;
+                    }
+                }
+            }
+            SortDirection::Descending => {
+                // The map only iterates forwards, so step below the previous key each time
+                let mut bound = after;
+                loop {
+                    let next = match bound {
+                        Some(id) => storage.iter_upper_bound(&id).next(),
+                        None => storage.last_key_value(),
+                    };
+                    let Some((id, _)) = next else { break };
+                    if !visit(id) {
+                        break;
+                    }
+                    bound = Some(id);
+                }
+            }
+        }
+    });
+}
+
+pub(crate) fn matches_filter(document: &Document, filter: &ListFilter) -> bool {
+    let updated_at = document.updated_at.unwrap_or(document.created_at);
+    let deletion = match filter.deletion {
+        DeletionFilter::Active => !document.is_deleted,
+        DeletionFilter::Deleted => document.is_deleted,
+        DeletionFilter::Any => true,
+    };
+
+    deletion
+        && filter.created_after.is_none_or(|after| document.created_at >= after)
+        && filter.created_before.is_none_or(|before| document.created_at <= before)
+        && filter.updated_after.is_none_or(|after| updated_at >= after)
+        && filter.updated_before.is_none_or(|before| updated_at <= before)
+        && filter.owner.is_none_or(|owner| document.owner == owner)
+}
+
+// Active documents are listed to readers; soft-deleted ones only to owners, who can restore them
+fn can_list(document: &Document, principal: &Principal) -> bool {
+    let required = if document.is_deleted { Role::Owner } else { Role::Reader };
+    has_role(document, principal, required)
+}
+
+fn sort_key(document: &Document, sort_by: SortField) -> SortKey {
+    match sort_by {
+        SortField::CreatedAt => SortKey::Number(document.created_at),
+        SortField::UpdatedAt => SortKey::Number(document.updated_at.unwrap_or(document.created_at)),
+        SortField::Title => SortKey::Text(document.title.to_lowercase()),
+        SortField::Version => SortKey::Number(document.version),
+        SortField::DeletedAt => {
+            SortKey::Number(document.deletion.as_ref().map_or(0, |deletion| deletion.deleted_at))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_document;
+
+    fn ids(sort_by: SortField, cursor: Option<ListCursor>, direction: SortDirection) -> Vec<u64> {
+        let mut ids = Vec::new();
+        walk(sort_by, cursor.as_ref(), direction, |_, id| {
+            ids.push(id);
+            true
+        });
+        ids
+    }
+
+    #[test]
+    fn walks_the_sort_index_after_the_cursor() {
+        let documents = [
+            test_document(1, "beta", "", 3),
+            test_document(2, "Alpha", "", 1),
+            test_document(3, "gamma", "", 3),
+        ];
+        for document in &documents {
+            index_document(document);
+        }
+        let ascending = SortDirection::Ascending;
+        let descending = SortDirection::Descending;
+
+        assert_eq!(ids(SortField::Title, None, ascending), vec![2, 1, 3]);
+        assert_eq!(ids(SortField::Title, None, descending), vec![3, 1, 2]);
+        assert_eq!(ids(SortField::Version, None, ascending), vec![2, 1, 3]);
+        assert_eq!(ids(SortField::Version, None, descending), vec![3, 1, 2]);
+
+        let cursor = ListCursor { key: SortKey::Number(3), id: 1 };
+        assert_eq!(ids(SortField::Version, Some(cursor.clone()), ascending), vec![3]);
+        assert_eq!(ids(SortField::Version, Some(cursor), descending), vec![2]);
+
+        // A retitled document moves to its new position
+        unindex_document(&documents[1]);
+        index_document(&test_document(2, "zeta", "", 2));
+        assert_eq!(ids(SortField::Title, None, ascending), vec![1, 3, 2]);
+        assert_eq!(ids(SortField::UpdatedAt, None, descending), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn walks_trash_entries_up_to_the_cutoff() {
+        let deleted = |id: u64, deleted_at: u64| {
+            let mut document = test_document(id, "trashed", "", 1);
+            document.is_deleted = true;
+            document.deletion = Some(crate::DeletionRecord {
+                deleted_by: Principal::anonymous(),
+                deleted_at,
+                reason: None,
+            });
+            document
+        };
+        let documents =
+            [deleted(10, 150), deleted(11, 50), test_document(12, "kept", "", 1), deleted(13, 100)];
+        for document in &documents {
+            index_document(document);
+        }
+
+        let mut ids = Vec::new();
+        walk_deleted_before(100, |id| {
+            ids.push(id);
+            true
+        });
+        assert_eq!(ids, vec![11, 13]);
+    }
+}
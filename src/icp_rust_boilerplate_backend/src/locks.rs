@@ -0,0 +1,164 @@
+// Check-out / check-in locking of documents with expiring leases.
+//
+// A checked-out document can only be edited or deleted by the lock holder until the
+// lease is checked in, broken by an admin or expires. Expired leases no longer block
+// anyone and are cleaned up by a periodic timer.
+use crate::audit::{self, AuditOperation};
+use crate::{
+    authenticate, authorize, authorize_admin, load_document, Error, Memory, Role, MEMORY_MANAGER,
+    NANOS_PER_SECOND,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Lease length used when the caller does not ask for one
+const DEFAULT_LEASE_SECONDS: u64 = 60 * 60;
+
+// Longest lease a caller can ask for
+const MAX_LEASE_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+// How often expired leases are removed
+const RELEASE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Lock {
+    document_id: u64,
+    holder: Principal,
+    acquired_at: u64,
+    expires_at: u64,
+}
+
+// Storable trait for Lock
+impl Storable for Lock {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for Lock
+impl BoundedStorable for Lock {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static LOCKS: RefCell<StableBTreeMap<u64, Lock, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+}
+
+pub(crate) fn start_timers() {
+    ic_cdk_timers::set_timer_interval(RELEASE_INTERVAL, release_expired_locks);
+}
+
+// Take an exclusive editing lease on a document, or renew the caller's own lease
+#[ic_cdk::update]
+fn checkout_document(id: u64, lease_seconds: Option<u64>) -> Result<Lock, Error> {
+    audit::audited(AuditOperation::CheckoutDocument, Some(id), || {
+        authenticate()?;
+        let document = load_document(id)?;
+        if document.is_deleted {
+            return Err(Error::DocumentDeleted);
+        }
+        authorize(&document, Role::Editor)?;
+        ensure_not_locked_by_other(id)?;
+
+        let lease_seconds = lease_seconds.unwrap_or(DEFAULT_LEASE_SECONDS);
+        if lease_seconds == 0 || lease_seconds > MAX_LEASE_SECONDS {
+            return Err(Error::InvalidArgument {
+                msg: format!("Lease must be between 1 and {} seconds", MAX_LEASE_SECONDS),
+            });
+        }
+
+        let lock = Lock {
+            document_id: id,
+            holder: caller(),
+            acquired_at: time(),
+            expires_at: time().saturating_add(lease_seconds.saturating_mul(NANOS_PER_SECOND)),
+        };
+        LOCKS.with(|locks| locks.borrow_mut().insert(id, lock.clone()));
+        Ok(lock)
+    })
+}
+
+// Release the caller's lease on a document
+#[ic_cdk::update]
+fn checkin_document(id: u64) -> Result<(), Error> {
+    audit::audited(AuditOperation::CheckinDocument, Some(id), || {
+        authenticate()?;
+        match active_lock(id) {
+            Some(lock) if lock.holder == caller() => {
+                LOCKS.with(|locks| locks.borrow_mut().remove(&id));
+                Ok(())
+            }
+            Some(lock) => Err(locked(&lock)),
+            None => Err(Error::NotFound { msg: format!("Document {} is not checked out", id) }),
+        }
+    })
+}
+
+// Remove the lease held on a document regardless of its holder; admin only
+#[ic_cdk::update]
+fn break_lock(id: u64) -> Result<Lock, Error> {
+    audit::audited(AuditOperation::BreakLock, Some(id), || {
+        authorize_admin()?;
+        LOCKS
+            .with(|locks| locks.borrow_mut().remove(&id))
+            .ok_or_else(|| Error::NotFound { msg: format!("Document {} is not checked out", id) })
+    })
+}
+
+// Current lease on a document, if any
+#[ic_cdk::query]
+fn get_lock(id: u64) -> Result<Option<Lock>, Error> {
+    let document = load_document(id)?;
+    authorize(&document, Role::Reader)?;
+    Ok(active_lock(id))
+}
+
+// Fail with Error::Locked if someone other than the caller holds a live lease
+pub(crate) fn ensure_not_locked_by_other(id: u64) -> Result<(), Error> {
+    match active_lock(id) {
+        Some(lock) if lock.holder != caller() => Err(locked(&lock)),
+        _ => Ok(()),
+    }
+}
+
+// Drop the lease of a document that no longer exists
+pub(crate) fn remove_lock(id: u64) {
+    LOCKS.with(|locks| locks.borrow_mut().remove(&id));
+}
+
+fn active_lock(id: u64) -> Option<Lock> {
+    LOCKS
+        .with(|locks| locks.borrow().get(&id))
+        .filter(|lock| lock.expires_at > time())
+}
+
+fn locked(lock: &Lock) -> Error {
+    Error::Locked { holder: lock.holder, expires_at: lock.expires_at }
+}
+
+fn release_expired_locks() {
+    let now = time();
+    LOCKS.with(|locks| {
+        let mut locks = locks.borrow_mut();
+        let expired: Vec<u64> = locks
+            .iter()
+            .filter(|(_, lock)| lock.expires_at <= now)
+            .map(|(id, _)| id)
+            .collect();
+        for id in expired {
+            locks.remove(&id);
+        }
+    });
+}
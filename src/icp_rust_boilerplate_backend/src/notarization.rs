@@ -0,0 +1,363 @@
+// Notarization receipts signed with the subnet's threshold ECDSA key.
+//
+// A receipt states that a version of a document with a given hash existed when it
+// was notarized. The canister signs the SHA-256 digest of the receipt with
+// `sign_with_ecdsa`, so anyone holding the receipt, the signature and the canister's
+// public key can check it offline, long after the document itself is gone. Every
+// signature costs the canister the threshold signing fee, so only owners and admins can
+// notarize, and owners within daily quotas.
+use crate::audit::{self, AuditOperation};
+use crate::integrity::write_field;
+use crate::{
+    authenticate, authorize, is_admin, load_document, load_version, principal_key, DocumentVersion,
+    Error, Memory, PrincipalKey, Role, MEMORY_MANAGER, NANOS_PER_SECOND,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Threshold ECDSA key used until the `ecdsa_key_name` install argument sets another;
+// `dfx_test_key` is the key of the local replica, mainnet deployments use `test_key_1`
+// or `key_1`
+const DEFAULT_KEY_NAME: &str = "dfx_test_key";
+
+// Domain separator that keeps receipt digests apart from any other signed message
+const RECEIPT_DOMAIN: &[u8] = b"document-notarization-receipt";
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * NANOS_PER_SECOND;
+
+// Notarizations a principal can request per day; admins are not limited
+const MAX_NOTARIZATIONS_PER_PRINCIPAL_PER_DAY: u64 = 20;
+
+// Notarizations the canister signs per day for all non-admin principals together
+const MAX_NOTARIZATIONS_PER_DAY: u64 = 500;
+
+// What the canister attests to; this is the signed message
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct NotarizationReceipt {
+    document_id: u64,
+    version: u64,
+    // SHA-256 over the version's title, description and file URL, each prefixed with
+    // its length in bytes as a big-endian u64, so anyone holding those fields can
+    // recompute it
+    content_hash: ByteBuf,
+    // Hash of the notarized version in the document's history hash chain; it also
+    // covers the author, the time of the version and the previous version's hash
+    version_hash: ByteBuf,
+    timestamp: u64,
+    canister_id: Principal,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Notarization {
+    receipt: NotarizationReceipt,
+    // 64-byte r || s signature over the SHA-256 digest of the receipt
+    signature: ByteBuf,
+    // SEC1 compressed secp256k1 public key of the canister
+    public_key: ByteBuf,
+    notarized_by: Principal,
+}
+
+// Notarizations requested during a day, counted from midnight UTC
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct DailyUsage {
+    day: u64,
+    count: u64,
+}
+
+// Storable trait for DailyUsage
+impl Storable for DailyUsage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for DailyUsage
+impl BoundedStorable for DailyUsage {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable trait for Notarization
+impl Storable for Notarization {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for Notarization
+impl BoundedStorable for Notarization {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Receipts keyed by (document id, sequence within the document)
+    static NOTARIZATIONS: RefCell<StableBTreeMap<(u64, u64), Notarization, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // The canister's public key, fetched on the first notarization; empty until then
+    static PUBLIC_KEY: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))), Vec::new())
+            .expect("Cannot create the notary public key")
+    );
+
+    static KEY_NAME: RefCell<Cell<String, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+            DEFAULT_KEY_NAME.to_string(),
+        )
+        .expect("Cannot create the notary key name")
+    );
+
+    static PRINCIPAL_USAGE: RefCell<StableBTreeMap<PrincipalKey, DailyUsage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    static TOTAL_USAGE: RefCell<Cell<DailyUsage, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+            DailyUsage::default(),
+        )
+        .expect("Cannot create the notarization usage")
+    );
+}
+
+// Sign a receipt for a version of a document; owners and admins only
+#[ic_cdk::update]
+async fn notarize_document(id: u64, version: u64) -> Result<Notarization, Error> {
+    let result = notarize(id, version).await;
+    audit::record_result(AuditOperation::NotarizeDocument, Some(id), &result);
+    result
+}
+
+async fn notarize(id: u64, version: u64) -> Result<Notarization, Error> {
+    authenticate()?;
+    let document = load_document(id)?;
+    if document.is_deleted {
+        return Err(Error::DocumentDeleted);
+    }
+    let notarized_by = caller();
+    if !is_admin(&notarized_by) {
+        authorize(&document, Role::Owner)?;
+    }
+    let version = load_version(id, version)?;
+    if !is_admin(&notarized_by) {
+        consume_quota(&notarized_by)?;
+    }
+
+    let receipt = NotarizationReceipt {
+        document_id: id,
+        version: version.version,
+        content_hash: ByteBuf::from(content_hash(&version).to_vec()),
+        version_hash: version.hash,
+        timestamp: time(),
+        canister_id: ic_cdk::id(),
+    };
+    let public_key = public_key().await?;
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: receipt_digest(&receipt).to_vec(),
+        derivation_path: Vec::new(),
+        key_id: key_id(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::SigningFailed { msg: format!("{:?}: {}", code, msg) })?;
+
+    // The document may have been purged while the signature was being produced
+    load_document(id)?;
+    let notarization = Notarization {
+        receipt,
+        signature: ByteBuf::from(response.signature),
+        public_key: ByteBuf::from(public_key),
+        notarized_by,
+    };
+    NOTARIZATIONS.with(|notarizations| {
+        let mut notarizations = notarizations.borrow_mut();
+        let sequence = notarizations
+            .range((id, 0)..=(id, u64::MAX))
+            .last()
+            .map_or(0, |((_, sequence), _)| sequence + 1);
+        notarizations.insert((id, sequence), notarization.clone());
+    });
+    Ok(notarization)
+}
+
+// Receipts issued for a document, oldest first
+#[ic_cdk::query]
+fn list_notarizations(id: u64) -> Result<Vec<Notarization>, Error> {
+    let document = load_document(id)?;
+    authorize(&document, Role::Reader)?;
+
+    Ok(NOTARIZATIONS.with(|notarizations| {
+        notarizations
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, notarization)| notarization)
+            .collect()
+    }))
+}
+
+// SEC1 compressed public key the receipts are signed with
+#[ic_cdk::query]
+fn get_notary_public_key() -> Result<ByteBuf, Error> {
+    cached_public_key().map(ByteBuf::from).ok_or_else(|| Error::NotFound {
+        msg: "No receipt has been notarized yet".to_string(),
+    })
+}
+
+// Check that a signature over a receipt was produced by this canister's key
+#[ic_cdk::query]
+fn verify_notarization(receipt: NotarizationReceipt, signature: ByteBuf) -> Result<bool, Error> {
+    let public_key = get_notary_public_key()?;
+    let key = VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| Error::InvalidArgument {
+        msg: "The notary public key is malformed".to_string(),
+    })?;
+    let Ok(signature) = Signature::from_slice(&signature) else {
+        return Ok(false);
+    };
+    let signature = signature.normalize_s().unwrap_or(signature);
+    Ok(key.verify_prehash(&receipt_digest(&receipt), &signature).is_ok())
+}
+
+// Drop the receipts of a document that no longer exists
+pub(crate) fn remove_notarizations(id: u64) {
+    NOTARIZATIONS.with(|notarizations| {
+        let mut notarizations = notarizations.borrow_mut();
+        let keys: Vec<(u64, u64)> =
+            notarizations.range((id, 0)..=(id, u64::MAX)).map(|(key, _)| key).collect();
+        for key in keys {
+            notarizations.remove(&key);
+        }
+    });
+}
+
+// SHA-256 over the domain separator and the length-prefixed fields of the receipt
+fn receipt_digest(receipt: &NotarizationReceipt) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    write_field(&mut hasher, RECEIPT_DOMAIN);
+    write_field(&mut hasher, &receipt.document_id.to_be_bytes());
+    write_field(&mut hasher, &receipt.version.to_be_bytes());
+    write_field(&mut hasher, &receipt.content_hash);
+    write_field(&mut hasher, &receipt.version_hash);
+    write_field(&mut hasher, &receipt.timestamp.to_be_bytes());
+    write_field(&mut hasher, receipt.canister_id.as_slice());
+    hasher.finalize().into()
+}
+
+fn content_hash(version: &DocumentVersion) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    write_field(&mut hasher, version.title.as_bytes());
+    write_field(&mut hasher, version.description.as_bytes());
+    write_field(&mut hasher, version.file_url.as_bytes());
+    hasher.finalize().into()
+}
+
+// Count a notarization against the principal's and the canister's daily quotas. The
+// quota is spent even if signing fails, since the attempt may still cost cycles.
+fn consume_quota(principal: &Principal) -> Result<(), Error> {
+    let day = time() / NANOS_PER_DAY;
+    let key = principal_key(principal);
+    // Usage recorded on an earlier day no longer counts
+    let today = |usage: DailyUsage| {
+        if usage.day == day {
+            usage
+        } else {
+            DailyUsage { day, count: 0 }
+        }
+    };
+    let stored = PRINCIPAL_USAGE.with(|usage| usage.borrow().get(&key));
+    let mut usage = today(stored.unwrap_or_default());
+    let mut total = today(TOTAL_USAGE.with(|cell| *cell.borrow().get()));
+
+    if usage.count >= MAX_NOTARIZATIONS_PER_PRINCIPAL_PER_DAY {
+        return Err(Error::QuotaExceeded {
+            msg: format!(
+                "Principal {} can notarize at most {} versions per day",
+                principal, MAX_NOTARIZATIONS_PER_PRINCIPAL_PER_DAY
+            ),
+        });
+    }
+    if total.count >= MAX_NOTARIZATIONS_PER_DAY {
+        return Err(Error::QuotaExceeded {
+            msg: format!(
+                "The canister notarizes at most {} versions per day",
+                MAX_NOTARIZATIONS_PER_DAY
+            ),
+        });
+    }
+
+    usage.count += 1;
+    total.count += 1;
+    PRINCIPAL_USAGE.with(|map| map.borrow_mut().insert(key, usage));
+    TOTAL_USAGE
+        .with(|cell| cell.borrow_mut().set(total))
+        .expect("cannot update the notarization usage");
+    Ok(())
+}
+
+// Switch to another threshold ECDSA key. Receipts already issued keep the public key
+// they were signed with; `get_notary_public_key` reports the new key once it is used.
+pub(crate) fn set_key_name(name: String) {
+    if name.trim().is_empty() {
+        ic_cdk::trap("The ECDSA key name cannot be empty");
+    }
+    if KEY_NAME.with(|cell| *cell.borrow().get() == name) {
+        return;
+    }
+    KEY_NAME
+        .with(|cell| cell.borrow_mut().set(name))
+        .expect("cannot store the notary key name");
+    PUBLIC_KEY
+        .with(|cell| cell.borrow_mut().set(Vec::new()))
+        .expect("cannot reset the notary public key");
+}
+
+fn key_id() -> EcdsaKeyId {
+    let name = KEY_NAME.with(|cell| cell.borrow().get().clone());
+    EcdsaKeyId { curve: EcdsaCurve::Secp256k1, name }
+}
+
+fn cached_public_key() -> Option<Vec<u8>> {
+    let public_key = PUBLIC_KEY.with(|cell| cell.borrow().get().clone());
+    (!public_key.is_empty()).then_some(public_key)
+}
+
+// The canister's public key, asking the management canister the first time
+async fn public_key() -> Result<Vec<u8>, Error> {
+    if let Some(public_key) = cached_public_key() {
+        return Ok(public_key);
+    }
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: Vec::new(),
+        key_id: key_id(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::SigningFailed { msg: format!("{:?}: {}", code, msg) })?;
+
+    PUBLIC_KEY
+        .with(|cell| cell.borrow_mut().set(response.public_key.clone()))
+        .expect("cannot store the notary public key");
+    Ok(response.public_key)
+}
@@ -0,0 +1,128 @@
+// Permanent removal of soft-deleted documents.
+//
+// Documents stay in the trash for a configurable retention period, after which a
+// periodic timer purges them together with their history, events, permissions and
+// content. Owners and admins can also purge a trashed document right away, and admins
+// can purge a record that can no longer be decoded.
+use crate::audit::{self, AuditOperation, AuditOutcome};
+use crate::changes::{self, ChangeKind};
+use crate::{
+    authenticate, authorize, authorize_admin, content, do_remove_document, holds, icrc3, is_admin,
+    listing, load_document, Error, Memory, Role, MEMORY_MANAGER, NANOS_PER_SECOND,
+};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+use std::time::Duration;
+
+// Retention period used until an admin configures one
+const DEFAULT_TRASH_RETENTION_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+// How often the retention job looks for expired documents
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Maximum number of documents purged by a single run of the retention job
+const MAX_PURGES_PER_RUN: usize = 50;
+
+// Maximum number of expired trash entries a single run looks at, held documents included
+const MAX_PURGE_SCAN: usize = 1_000;
+
+thread_local! {
+    static TRASH_RETENTION_SECONDS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+            DEFAULT_TRASH_RETENTION_SECONDS,
+        )
+        .expect("Cannot create the trash retention setting")
+    );
+}
+
+// Start the periodic retention job; timers do not survive upgrades, so this runs
+// on both install and upgrade
+pub(crate) fn start_timers() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, purge_expired_documents);
+}
+
+// Number of seconds a soft-deleted document is kept before being purged
+#[ic_cdk::query]
+fn get_trash_retention() -> u64 {
+    TRASH_RETENTION_SECONDS.with(|cell| *cell.borrow().get())
+}
+
+// Change the trash retention period; admin only
+#[ic_cdk::update]
+fn set_trash_retention(seconds: u64) -> Result<u64, Error> {
+    audit::audited(AuditOperation::SetTrashRetention, None, || {
+        authorize_admin()?;
+        TRASH_RETENTION_SECONDS
+            .with(|cell| cell.borrow_mut().set(seconds))
+            .expect("cannot update the trash retention setting");
+        Ok(seconds)
+    })
+}
+
+// Permanently remove a soft-deleted document; owner or admin only. Admins can also
+// remove a document whose record cannot be decoded, deleted or not.
+#[ic_cdk::update]
+fn purge_document(id: u64) -> Result<(), Error> {
+    audit::audited(AuditOperation::PurgeDocument, Some(id), || {
+        authenticate()?;
+        let document = match load_document(id) {
+            Err(Error::CorruptRecord { .. }) if is_admin(&caller()) => {
+                holds::ensure_not_held(id)?;
+                // The version is unknown without the record
+                purge(id, 0);
+                return Ok(());
+            }
+            result => result?,
+        };
+        if !is_admin(&caller()) {
+            authorize(&document, Role::Owner)?;
+        }
+        if !document.is_deleted {
+            return Err(Error::NotDeleted);
+        }
+        holds::ensure_not_held(id)?;
+        purge(id, document.version);
+        Ok(())
+    })
+}
+
+// Purge documents whose retention period has elapsed, a bounded batch per run. Only
+// the trash entries of the sort index up to the retention cutoff are looked at.
+fn purge_expired_documents() {
+    let retention = get_trash_retention().saturating_mul(NANOS_PER_SECOND);
+    let Some(cutoff) = time().checked_sub(retention) else { return };
+    let mut expired = Vec::new();
+    let mut scanned = 0;
+    listing::walk_deleted_before(cutoff, |id| {
+        scanned += 1;
+        // Held documents stay in the trash until every hold on them is lifted
+        if !holds::is_held(id) {
+            let document = load_document(id).ok().filter(|document| {
+                document.deletion.as_ref().is_some_and(|deletion| deletion.deleted_at <= cutoff)
+            });
+            expired.extend(document);
+        }
+        expired.len() < MAX_PURGES_PER_RUN && scanned < MAX_PURGE_SCAN
+    });
+
+    for document in expired {
+        purge(document.id, document.version);
+        audit::record(
+            ic_cdk::id(),
+            AuditOperation::PurgeDocument,
+            Some(document.id),
+            AuditOutcome::Success,
+        );
+    }
+}
+
+fn purge(id: u64, version: u64) {
+    for content_id in do_remove_document(id) {
+        content::delete_content(content_id);
+    }
+    icrc3::log_document_block(icrc3::BTYPE_PURGE, id, Vec::new());
+    changes::record(id, ChangeKind::Purged, version, None);
+}
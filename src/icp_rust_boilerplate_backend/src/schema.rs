@@ -0,0 +1,631 @@
+// Versioned storage envelope and schema migrations for stored documents.
+//
+// Documents are written as ENVELOPE_MAGIC, the big-endian record version and the
+// Candid encoding of the record. Records written before the envelope existed start
+// with Candid's own "DIDL" magic and are read with the version 1 layout; records of
+// the original release, which kept their versions inline, are converted by the
+// version 1 migration. To change the document layout, bump RECORD_VERSION, keep a
+// decoder for the previous layout in `decode_document` and register a migration that
+// rewrites STORAGE. Records are moved to maps on new memory ids when they outgrow the
+// MAX_SIZE of their map, which cannot change once the map exists. Every migration
+// bumps SCHEMA_VERSION; post_upgrade runs every migration newer than the stored
+// schema version, in order. Records are decoded when read rather than by the map, so
+// an undecodable one surfaces as Error::CorruptRecord instead of trapping the call.
+use crate::{
+    append_version, content, do_insert_document, envelopes, integrity, listing, search,
+    DeletionRecord, Document, DocumentEvent, DocumentEventKind, DocumentMetadata, DocumentVersion,
+    Error, Memory, EVENTS, HISTORY, MEMORY_MANAGER, STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use serde_bytes::ByteBuf;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Schema version of the stable memory this release writes
+pub(crate) const SCHEMA_VERSION: u32 = 6;
+
+// Layout version written in the envelope of document records
+const RECORD_VERSION: u32 = 1;
+
+// Prefix of enveloped records; cannot be confused with Candid's "DIDL"
+const ENVELOPE_MAGIC: &[u8; 3] = b"DSV";
+
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+
+// Largest stored document record, envelope included
+pub(crate) const MAX_DOCUMENT_SIZE: u32 = 4096;
+
+// Largest stored version record
+pub(crate) const MAX_VERSION_SIZE: u32 = 4096;
+
+// Sizes of the records in the maps STORAGE and HISTORY replaced
+const RETIRED_DOCUMENT_SIZE: u32 = 1024;
+const RETIRED_VERSION_SIZE: u32 = 2048;
+
+struct Migration {
+    // Schema version the migration brings the records to
+    version: u32,
+    description: &'static str,
+    run: fn(&MigrationContext),
+}
+
+struct MigrationContext {
+    // Owner given to documents of the original release, which had none
+    legacy_owner: Principal,
+}
+
+// Migrations in version order
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Wrap stored documents in the versioned envelope and move the inline \
+                      history of original documents to the history map",
+        run: rewrite_documents,
+    },
+    Migration {
+        version: 2,
+        description: "Build the sort index used by listings",
+        run: build_sort_index,
+    },
+    Migration {
+        version: 3,
+        description: "Index envelopes by document and pending envelopes by deadline",
+        run: build_envelope_indexes,
+    },
+    Migration {
+        version: 4,
+        description: "Schedule the expiry of content not attached to a document",
+        run: schedule_content_expiry,
+    },
+    Migration {
+        version: 5,
+        description: "Move documents and versions to maps with room for longer fields",
+        run: move_records,
+    },
+    Migration {
+        version: 6,
+        description: "Count the bytes of unattached content held across the canister",
+        run: count_unattached_bytes,
+    },
+];
+
+// Document layout of the original release, with its versions kept inline
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyDocument {
+    id: u64,
+    title: String,
+    description: String,
+    file_url: String,
+    version: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+    is_deleted: bool,
+    history: Vec<LegacyVersion>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyVersion {
+    version: u64,
+    title: String,
+    description: String,
+    file_url: String,
+    metadata: LegacyMetadata,
+    updated_at: u64,
+}
+
+// `updated_by` was a free-form label supplied by the client
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyMetadata {
+    updated_by: String,
+    change_summary: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AppliedMigration {
+    version: u32,
+    description: String,
+    applied_at: u64,
+}
+
+// Schema version of the stored records and the migrations that brought them there
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct SchemaInfo {
+    version: u32,
+    applied_migrations: Vec<AppliedMigration>,
+}
+
+// A document as kept in STORAGE: its enveloped encoding
+pub(crate) struct StoredDocument(Vec<u8>);
+
+impl StoredDocument {
+    // Payload limits keep every document within MAX_DOCUMENT_SIZE
+    pub(crate) fn new(document: &Document) -> Self {
+        let mut bytes = ENVELOPE_MAGIC.to_vec();
+        bytes.extend_from_slice(&RECORD_VERSION.to_be_bytes());
+        bytes.extend(Encode!(document).expect("cannot encode document"));
+        StoredDocument(bytes)
+    }
+
+    pub(crate) fn decode(&self, id: u64) -> Result<Document, Error> {
+        decode_document(&self.0).ok_or(Error::CorruptRecord { id })
+    }
+}
+
+// Storable trait for StoredDocument
+impl Storable for StoredDocument {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredDocument(bytes.into_owned())
+    }
+}
+
+// BoundedStorable trait for StoredDocument
+impl BoundedStorable for StoredDocument {
+    const MAX_SIZE: u32 = MAX_DOCUMENT_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A version as kept in HISTORY: its Candid encoding, without an envelope
+pub(crate) struct StoredVersion(Vec<u8>);
+
+impl StoredVersion {
+    // Payload limits keep every version within MAX_VERSION_SIZE
+    pub(crate) fn new(version: &DocumentVersion) -> Self {
+        StoredVersion(Encode!(version).expect("cannot encode version"))
+    }
+
+    // `id` is the document the version belongs to
+    pub(crate) fn decode(&self, id: u64) -> Result<DocumentVersion, Error> {
+        Decode!(&self.0, DocumentVersion).map_err(|_| Error::CorruptRecord { id })
+    }
+}
+
+// Storable trait for StoredVersion
+impl Storable for StoredVersion {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredVersion(bytes.into_owned())
+    }
+}
+
+// BoundedStorable trait for StoredVersion
+impl BoundedStorable for StoredVersion {
+    const MAX_SIZE: u32 = MAX_VERSION_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Raw record of a map that was replaced by a map with larger records
+struct RetiredRecord<const MAX_SIZE: u32>(Vec<u8>);
+
+type RetiredDocument = RetiredRecord<RETIRED_DOCUMENT_SIZE>;
+type RetiredVersion = RetiredRecord<RETIRED_VERSION_SIZE>;
+
+// Storable trait for RetiredRecord
+impl<const MAX_SIZE: u32> Storable for RetiredRecord<MAX_SIZE> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RetiredRecord(bytes.into_owned())
+    }
+}
+
+// BoundedStorable trait for RetiredRecord
+impl<const MAX_SIZE: u32> BoundedStorable for RetiredRecord<MAX_SIZE> {
+    const MAX_SIZE: u32 = MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable trait for SchemaInfo
+impl Storable for SchemaInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    // Version 0 until the first upgrade to a release with schema versioning
+    static SCHEMA: RefCell<Cell<SchemaInfo, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+            SchemaInfo::default(),
+        )
+        .expect("Cannot create the schema version")
+    );
+
+    // Documents as stored before schema version 5, including those of the original
+    // release; only read by the migrations that empty them
+    static RETIRED_STORAGE: RefCell<StableBTreeMap<u64, RetiredDocument, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    // Versions as stored before schema version 5
+    static RETIRED_HISTORY: RefCell<StableBTreeMap<(u64, u64), RetiredVersion, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+}
+
+// Schema version of the stored records and the migrations applied so far
+#[ic_cdk::query]
+fn get_schema_version() -> SchemaInfo {
+    SCHEMA.with(|schema| schema.borrow().get().clone())
+}
+
+// Record that the stored records are at this release's schema version; done on a fresh
+// install, which has nothing to migrate, and before handing the data to the next release
+pub(crate) fn stamp_version() {
+    update_schema(|schema| schema.version = SCHEMA_VERSION);
+}
+
+// Run the migrations newer than the stored schema version. Refuses to start on data
+// written by a newer release, which would be misread. Documents of the original release
+// are given to `legacy_owner`.
+pub(crate) fn migrate(legacy_owner: Principal) {
+    let context = MigrationContext { legacy_owner };
+    let stored = SCHEMA.with(|schema| schema.borrow().get().version);
+    if stored > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "Stored schema version {} is newer than this release's version {}",
+            stored, SCHEMA_VERSION
+        ));
+    }
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > stored) {
+        (migration.run)(&context);
+        update_schema(|schema| {
+            schema.version = migration.version;
+            schema.applied_migrations.push(AppliedMigration {
+                version: migration.version,
+                description: migration.description.to_string(),
+                applied_at: time(),
+            });
+        });
+    }
+}
+
+// Decode a map entry, skipping corrupt records; for scans over STORAGE
+pub(crate) fn decoded((id, stored): (u64, StoredDocument)) -> Option<(u64, Document)> {
+    stored.decode(id).ok().map(|document| (id, document))
+}
+
+fn decode_document(bytes: &[u8]) -> Option<Document> {
+    let (version, payload) = open_envelope(bytes)?;
+    match version {
+        1 => Decode!(payload, Document).ok(),
+        _ => None,
+    }
+}
+
+// Schema version and Candid payload of a stored record
+fn open_envelope(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    if bytes.starts_with(CANDID_MAGIC) {
+        return Some((1, bytes));
+    }
+    let payload = bytes.strip_prefix(ENVELOPE_MAGIC)?;
+    let (version, payload) = payload.split_first_chunk::<4>()?;
+    Some((u32::from_be_bytes(*version), payload))
+}
+
+// Move every document from the retired map to STORAGE with the current envelope,
+// converting documents of the original release; records that cannot be decoded either
+// way are moved as they are and reported
+fn rewrite_documents(context: &MigrationContext) {
+    let ids: Vec<u64> =
+        RETIRED_STORAGE.with(|retired| retired.borrow().iter().map(|(id, _)| id).collect());
+    for id in ids {
+        let Some(RetiredRecord(bytes)) =
+            RETIRED_STORAGE.with(|retired| retired.borrow_mut().remove(&id))
+        else {
+            continue;
+        };
+        let stored = StoredDocument(bytes);
+        if let Ok(document) = stored.decode(id) {
+            do_insert_document(&document);
+        } else if let Some(legacy) = decode_legacy(&stored.0) {
+            let document = convert_legacy(legacy, context.legacy_owner, time());
+            do_insert_document(&document);
+            if !document.is_deleted {
+                search::index_document(&document);
+            }
+        } else {
+            ic_cdk::println!("Document {} cannot be decoded and was moved as is", id);
+            STORAGE.with(|service| service.borrow_mut().insert(id, stored));
+        }
+    }
+}
+
+fn build_sort_index(_: &MigrationContext) {
+    listing::rebuild_index();
+}
+
+fn build_envelope_indexes(_: &MigrationContext) {
+    envelopes::rebuild_indexes();
+}
+
+fn schedule_content_expiry(_: &MigrationContext) {
+    content::rebuild_expiries();
+}
+
+fn count_unattached_bytes(_: &MigrationContext) {
+    content::rebuild_total_unattached_bytes();
+}
+
+// Versions are moved before the documents, whose rewrite does not read them
+fn move_records(context: &MigrationContext) {
+    let keys: Vec<(u64, u64)> =
+        RETIRED_HISTORY.with(|retired| retired.borrow().iter().map(|(key, _)| key).collect());
+    for key in keys {
+        if let Some(RetiredRecord(bytes)) =
+            RETIRED_HISTORY.with(|retired| retired.borrow_mut().remove(&key))
+        {
+            HISTORY.with(|history| history.borrow_mut().insert(key, StoredVersion(bytes)));
+        }
+    }
+    rewrite_documents(context);
+}
+
+fn decode_legacy(bytes: &[u8]) -> Option<LegacyDocument> {
+    Decode!(bytes, LegacyDocument).ok()
+}
+
+// Bring a document of the original release to the current layout: its inline versions
+// are chained into the history map and it gets the events it would have had if it had
+// been created by this release. The caller stores and indexes the returned document.
+fn convert_legacy(legacy: LegacyDocument, owner: Principal, now: u64) -> Document {
+    let mut document = Document {
+        id: legacy.id,
+        owner,
+        title: legacy.title,
+        description: legacy.description,
+        file_url: legacy.file_url,
+        content_id: None,
+        version: legacy.version,
+        created_at: legacy.created_at,
+        updated_at: legacy.updated_at,
+        updated_by: legacy.updated_at.map(|_| owner),
+        is_deleted: legacy.is_deleted,
+        // The original release did not record deletions, so retention starts now
+        deletion: legacy.is_deleted.then_some(DeletionRecord {
+            deleted_by: owner,
+            deleted_at: now,
+            reason: None,
+        }),
+        history_head: ByteBuf::from(integrity::GENESIS_HASH.to_vec()),
+    };
+
+    let mut versions = legacy.history;
+    versions.sort_by_key(|version| version.version);
+    versions.dedup_by_key(|version| version.version);
+    for version in versions {
+        let updated_by = version.metadata.updated_by;
+        append_version(
+            &mut document,
+            DocumentVersion {
+                version: version.version,
+                title: version.title,
+                description: version.description,
+                file_url: version.file_url,
+                content_id: None,
+                metadata: DocumentMetadata {
+                    display_name: (!updated_by.is_empty()).then_some(updated_by),
+                    change_summary: version.metadata.change_summary,
+                },
+                updated_by: owner,
+                updated_at: version.updated_at,
+                lifecycle: None,
+                previous_hash: ByteBuf::new(),
+                hash: ByteBuf::new(),
+            },
+        );
+    }
+    // The current version must be in the history even if the inline copy was missing
+    let has_current =
+        HISTORY.with(|history| history.borrow().contains_key(&(document.id, document.version)));
+    if !has_current {
+        let current = DocumentVersion {
+            version: document.version,
+            title: document.title.clone(),
+            description: document.description.clone(),
+            file_url: document.file_url.clone(),
+            content_id: None,
+            metadata: DocumentMetadata::default(),
+            updated_by: owner,
+            updated_at: document.updated_at.unwrap_or(document.created_at),
+            lifecycle: None,
+            previous_hash: ByteBuf::new(),
+            hash: ByteBuf::new(),
+        };
+        append_version(&mut document, current);
+    }
+
+    EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let event = |kind, timestamp| DocumentEvent { kind, actor: owner, timestamp };
+        events.insert((document.id, 0), event(DocumentEventKind::Created, document.created_at));
+        if let Some(deletion) = &document.deletion {
+            events.insert((document.id, 1), event(DocumentEventKind::Deleted, deletion.deleted_at));
+        }
+    });
+    document
+}
+
+fn update_schema(f: impl FnOnce(&mut SchemaInfo)) {
+    SCHEMA.with(|cell| {
+        let mut schema = cell.borrow().get().clone();
+        f(&mut schema);
+        cell.borrow_mut().set(schema).expect("cannot update the schema version");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::{self, LifecycleRecord, LifecycleState};
+    use crate::{integrity, load_version};
+
+    #[test]
+    fn opens_envelopes() {
+        let mut record = ENVELOPE_MAGIC.to_vec();
+        record.extend_from_slice(&7u32.to_be_bytes());
+        record.extend_from_slice(b"DIDL\x00");
+        assert_eq!(open_envelope(&record), Some((7, &b"DIDL\x00"[..])));
+
+        // Records from before the envelope are the bare Candid payload
+        assert_eq!(open_envelope(b"DIDL\x00\x01\x71"), Some((1, &b"DIDL\x00\x01\x71"[..])));
+
+        assert_eq!(open_envelope(b"DSV\x00\x00"), None);
+        assert_eq!(open_envelope(b"XYZ\x00\x00\x00\x01"), None);
+        assert_eq!(open_envelope(b""), None);
+    }
+
+    #[test]
+    fn largest_payloads_fit_their_records() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let text = |len| "x".repeat(len);
+        let hash = || ByteBuf::from(vec![0xff; 32]);
+        let document = Document {
+            id: u64::MAX,
+            owner,
+            title: text(crate::MAX_TITLE_LEN),
+            description: text(crate::MAX_DESCRIPTION_LEN),
+            file_url: text(crate::MAX_FILE_URL_LEN),
+            content_id: Some(u64::MAX),
+            version: u64::MAX,
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+            updated_by: Some(owner),
+            is_deleted: true,
+            deletion: Some(DeletionRecord {
+                deleted_by: owner,
+                deleted_at: u64::MAX,
+                reason: Some(text(crate::MAX_REASON_LEN)),
+            }),
+            history_head: hash(),
+        };
+        assert!(StoredDocument::new(&document).0.len() <= MAX_DOCUMENT_SIZE as usize);
+
+        let version = DocumentVersion {
+            version: u64::MAX,
+            title: document.title.clone(),
+            description: document.description.clone(),
+            file_url: document.file_url.clone(),
+            content_id: Some(u64::MAX),
+            metadata: DocumentMetadata {
+                display_name: Some(text(crate::MAX_DISPLAY_NAME_LEN)),
+                change_summary: text(crate::MAX_CHANGE_SUMMARY_LEN),
+            },
+            updated_by: owner,
+            updated_at: u64::MAX,
+            lifecycle: Some(LifecycleRecord {
+                state: LifecycleState::Approved,
+                approved_by: vec![owner; lifecycle::MAX_REVIEWERS],
+            }),
+            previous_hash: hash(),
+            hash: hash(),
+        };
+        assert!(StoredVersion::new(&version).0.len() <= MAX_VERSION_SIZE as usize);
+    }
+
+    // Document as encoded by the original release
+    #[derive(candid::CandidType, Serialize)]
+    struct BaselineDocument {
+        id: u64,
+        title: String,
+        description: String,
+        file_url: String,
+        version: u64,
+        created_at: u64,
+        updated_at: Option<u64>,
+        is_deleted: bool,
+        history: Vec<BaselineVersion>,
+    }
+
+    #[derive(candid::CandidType, Serialize)]
+    struct BaselineVersion {
+        version: u64,
+        title: String,
+        description: String,
+        file_url: String,
+        metadata: BaselineMetadata,
+        updated_at: u64,
+    }
+
+    #[derive(candid::CandidType, Serialize)]
+    struct BaselineMetadata {
+        updated_by: String,
+        change_summary: String,
+    }
+
+    fn baseline_version(version: u64, title: &str, updated_by: &str) -> BaselineVersion {
+        BaselineVersion {
+            version,
+            title: title.to_string(),
+            description: "Quarterly figures".to_string(),
+            file_url: "https://example.com/report.pdf".to_string(),
+            metadata: BaselineMetadata {
+                updated_by: updated_by.to_string(),
+                change_summary: format!("Version {}", version),
+            },
+            updated_at: version * 100,
+        }
+    }
+
+    #[test]
+    fn converts_baseline_documents() {
+        let baseline = BaselineDocument {
+            id: 42,
+            title: "Report v2".to_string(),
+            description: "Quarterly figures".to_string(),
+            file_url: "https://example.com/report.pdf".to_string(),
+            version: 2,
+            created_at: 100,
+            updated_at: Some(200),
+            is_deleted: true,
+            history: vec![
+                baseline_version(1, "Report", "alice"),
+                baseline_version(2, "Report v2", ""),
+            ],
+        };
+        let stored = StoredDocument(Encode!(&baseline).unwrap());
+
+        assert!(matches!(stored.decode(42), Err(Error::CorruptRecord { id: 42 })));
+        let legacy = decode_legacy(&stored.0).expect("baseline record should decode");
+        let owner = Principal::from_slice(&[1; 29]);
+        let document = convert_legacy(legacy, owner, 500);
+
+        assert_eq!(document.owner, owner);
+        assert_eq!(document.title, "Report v2");
+        assert_eq!(document.version, 2);
+        assert_eq!(document.updated_by, Some(owner));
+        let deletion = document.deletion.as_ref().expect("deletion should be recorded");
+        assert_eq!((deletion.deleted_by, deletion.deleted_at), (owner, 500));
+
+        let first = load_version(42, 1).ok().expect("version 1 should be migrated");
+        assert_eq!(first.title, "Report");
+        assert_eq!(first.metadata.display_name.as_deref(), Some("alice"));
+        assert_eq!(first.updated_by, owner);
+        let second = load_version(42, 2).ok().expect("version 2 should be migrated");
+        assert_eq!(second.metadata.display_name, None);
+        assert_eq!(second.previous_hash, first.hash);
+        assert_eq!(document.history_head, second.hash);
+        assert!(integrity::check_chain(&document, &mut 0).is_ok());
+
+        let reencoded = StoredDocument::new(&document);
+        let decoded = reencoded.decode(42).ok().expect("re-encoded document should decode");
+        assert_eq!(decoded.history_head, second.hash);
+    }
+}
@@ -0,0 +1,548 @@
+// Inverted full-text index over document titles and descriptions.
+//
+// Every active (not soft-deleted) document contributes one posting per distinct
+// term, keyed by (term, document id), so the documents containing a term are a
+// contiguous range of the map and queries never have to scan the document store.
+// Postings carry per-field term frequencies and the index keeps per-document field
+// lengths and corpus totals, which is everything BM25 needs to rank the matches.
+use crate::{has_role, load_document, Document, Memory, Role, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_cdk::api::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// Terms longer than this many bytes are truncated before indexing
+const MAX_TERM_LEN: usize = 32;
+
+// Maximum number of ranked results returned by a single search
+const MAX_SEARCH_RESULTS: usize = 50;
+
+// BM25 term frequency saturation and length normalization parameters
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+// A title match counts this many times as much as a description match
+const TITLE_WEIGHT: f64 = 2.0;
+
+// Approximate length, in characters, of a description snippet
+const SNIPPET_CHARS: usize = 160;
+
+// How the terms of a query are combined
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum SearchMode {
+    // Documents containing every term
+    All,
+    // Documents containing at least one term
+    Any,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum SearchField {
+    Title,
+    Description,
+}
+
+// Character range of a matched term inside a snippet, end exclusive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Highlight {
+    start: u32,
+    end: u32,
+}
+
+// Excerpt of a field around the matched terms
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Snippet {
+    field: SearchField,
+    text: String,
+    highlights: Vec<Highlight>,
+}
+
+// A matching document together with why and how well it matched
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SearchResult {
+    id: u64,
+    score: f64,
+    matched_fields: Vec<SearchField>,
+    snippets: Vec<Snippet>,
+}
+
+// A normalized token, bounded so it can be part of a stable map key
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Term(String);
+
+// Occurrences of a term in each field of a document
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct Posting {
+    title_tf: u32,
+    description_tf: u32,
+}
+
+// Number of terms in each field of a document
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct FieldLengths {
+    title: u32,
+    description: u32,
+}
+
+// Corpus-wide totals used for IDF and average field lengths
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct IndexStats {
+    documents: u64,
+    title_terms: u64,
+    description_terms: u64,
+}
+
+// Storable trait for Term
+impl Storable for Term {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Term(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+// BoundedStorable trait for Term
+impl BoundedStorable for Term {
+    const MAX_SIZE: u32 = MAX_TERM_LEN as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable trait for Posting
+impl Storable for Posting {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for Posting
+impl BoundedStorable for Posting {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable trait for FieldLengths
+impl Storable for FieldLengths {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for FieldLengths
+impl BoundedStorable for FieldLengths {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable trait for IndexStats
+impl Storable for IndexStats {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    // Postings keyed by (term, document id)
+    static INDEX: RefCell<StableBTreeMap<(Term, u64), Posting, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static FIELD_LENGTHS: RefCell<StableBTreeMap<u64, FieldLengths, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static INDEX_STATS: RefCell<Cell<IndexStats, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))), IndexStats::default())
+            .expect("Cannot create index stats")
+    );
+}
+
+// Search for documents by title or description, best matches first.
+// Only documents the caller can read are returned.
+#[ic_cdk::query]
+fn search_documents(query: String, mode: SearchMode) -> Vec<SearchResult> {
+    let terms = term_counts(&query);
+    if terms.is_empty() {
+        return Vec::new();
+    }
+
+    let mut scored = score_matches(&terms, mode);
+    scored.sort_by(|(a_id, a_score, _), (b_id, b_score, _)| {
+        b_score.total_cmp(a_score).then(a_id.cmp(b_id))
+    });
+
+    let principal = caller();
+    scored
+        .into_iter()
+        .filter_map(|(id, score, matched_fields)| {
+            let document = load_document(id).ok()?;
+            if document.is_deleted || !has_role(&document, &principal, Role::Reader) {
+                return None;
+            }
+            let snippets = matched_fields
+                .iter()
+                .filter_map(|field| snippet(&document, *field, &terms))
+                .collect();
+            Some(SearchResult { id, score, matched_fields, snippets })
+        })
+        .take(MAX_SEARCH_RESULTS)
+        .collect()
+}
+
+// Rank documents that are not in the index, such as past states of documents, the
+// same way `search_documents` ranks indexed ones; corpus statistics come from
+// `documents` alone
+pub(crate) fn search_snapshots(
+    query: &str,
+    mode: SearchMode,
+    documents: Vec<Document>,
+) -> Vec<SearchResult> {
+    let terms = term_counts(query);
+    if terms.is_empty() || documents.is_empty() {
+        return Vec::new();
+    }
+
+    let fields: Vec<(BTreeMap<Term, u32>, BTreeMap<Term, u32>)> = documents
+        .iter()
+        .map(|document| (term_counts(&document.title), term_counts(&document.description)))
+        .collect();
+    let count = documents.len() as f64;
+    let field_length = |counts: &BTreeMap<Term, u32>| counts.values().sum::<u32>();
+    let avg_title =
+        (fields.iter().map(|(title, _)| field_length(title) as f64).sum::<f64>() / count).max(1.0);
+    let avg_description = (fields
+        .iter()
+        .map(|(_, description)| field_length(description) as f64)
+        .sum::<f64>()
+        / count)
+        .max(1.0);
+    let idf: BTreeMap<&Term, f64> = terms
+        .keys()
+        .map(|term| {
+            let df = fields
+                .iter()
+                .filter(|(title, description)| {
+                    title.contains_key(term) || description.contains_key(term)
+                })
+                .count() as f64;
+            (term, (1.0 + (count - df + 0.5) / (df + 0.5)).ln())
+        })
+        .collect();
+
+    let mut results: Vec<SearchResult> = documents
+        .iter()
+        .zip(&fields)
+        .filter_map(|(document, (title, description))| {
+            let title_length = field_length(title);
+            let description_length = field_length(description);
+            let mut score = 0.0;
+            let mut matched_terms = 0;
+            for (term, idf) in &idf {
+                let title_tf = title.get(*term).copied().unwrap_or(0);
+                let description_tf = description.get(*term).copied().unwrap_or(0);
+                if title_tf == 0 && description_tf == 0 {
+                    continue;
+                }
+                matched_terms += 1;
+                score += idf
+                    * (TITLE_WEIGHT * bm25_tf(title_tf, title_length, avg_title)
+                        + bm25_tf(description_tf, description_length, avg_description));
+            }
+            let matched = match mode {
+                SearchMode::All => matched_terms == terms.len(),
+                SearchMode::Any => matched_terms > 0,
+            };
+            if !matched {
+                return None;
+            }
+
+            let snippets: Vec<Snippet> = [SearchField::Title, SearchField::Description]
+                .into_iter()
+                .filter_map(|field| snippet(document, field, &terms))
+                .collect();
+            let matched_fields = snippets.iter().map(|snippet| snippet.field).collect();
+            Some(SearchResult { id: document.id, score, matched_fields, snippets })
+        })
+        .collect();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)));
+    results.truncate(MAX_SEARCH_RESULTS);
+    results
+}
+
+// Add postings for every term of the document
+pub(crate) fn index_document(document: &Document) {
+    let title = term_counts(&document.title);
+    let description = term_counts(&document.description);
+    let lengths = FieldLengths {
+        title: title.values().sum(),
+        description: description.values().sum(),
+    };
+
+    INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for (term, posting) in postings(&title, &description) {
+            index.insert((term, document.id), posting);
+        }
+    });
+    FIELD_LENGTHS.with(|lengths_map| lengths_map.borrow_mut().insert(document.id, lengths));
+    update_stats(|stats| {
+        stats.documents += 1;
+        stats.title_terms += lengths.title as u64;
+        stats.description_terms += lengths.description as u64;
+    });
+}
+
+// Remove the postings added by `index_document` for the same document state
+pub(crate) fn unindex_document(document: &Document) {
+    let title = term_counts(&document.title);
+    let description = term_counts(&document.description);
+
+    INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for term in postings(&title, &description).into_keys() {
+            index.remove(&(term, document.id));
+        }
+    });
+    if let Some(lengths) = FIELD_LENGTHS.with(|lengths| lengths.borrow_mut().remove(&document.id)) {
+        update_stats(|stats| {
+            stats.documents = stats.documents.saturating_sub(1);
+            stats.title_terms = stats.title_terms.saturating_sub(lengths.title as u64);
+            stats.description_terms =
+                stats.description_terms.saturating_sub(lengths.description as u64);
+        });
+    }
+}
+
+fn update_stats(f: impl FnOnce(&mut IndexStats)) {
+    INDEX_STATS.with(|cell| {
+        let mut stats = *cell.borrow().get();
+        f(&mut stats);
+        cell.borrow_mut().set(stats).expect("cannot update index stats");
+    });
+}
+
+fn postings(
+    title: &BTreeMap<Term, u32>,
+    description: &BTreeMap<Term, u32>,
+) -> BTreeMap<Term, Posting> {
+    let mut postings: BTreeMap<Term, Posting> = BTreeMap::new();
+    for (term, count) in title {
+        postings.entry(term.clone()).or_default().title_tf = *count;
+    }
+    for (term, count) in description {
+        postings.entry(term.clone()).or_default().description_tf = *count;
+    }
+    postings
+}
+
+// Lowercase alphanumeric runs with their character ranges in the text
+fn tokens(text: &str) -> Vec<(Term, usize, usize)> {
+    let mut tokens = Vec::new();
+    let mut start: Option<(usize, usize)> = None;
+    let mut chars = 0;
+    for (byte, c) in text.char_indices() {
+        if c.is_alphanumeric() {
+            start.get_or_insert((byte, chars));
+        } else if let Some((start_byte, start_char)) = start.take() {
+            tokens.push((normalize(&text[start_byte..byte]), start_char, chars));
+        }
+        chars += 1;
+    }
+    if let Some((start_byte, start_char)) = start {
+        tokens.push((normalize(&text[start_byte..]), start_char, chars));
+    }
+    tokens
+}
+
+// Lowercase a token and truncate it to MAX_TERM_LEN bytes on a char boundary
+fn normalize(token: &str) -> Term {
+    let mut term = token.to_lowercase();
+    if term.len() > MAX_TERM_LEN {
+        let mut end = MAX_TERM_LEN;
+        while !term.is_char_boundary(end) {
+            end -= 1;
+        }
+        term.truncate(end);
+    }
+    Term(term)
+}
+
+fn term_counts(text: &str) -> BTreeMap<Term, u32> {
+    let mut counts = BTreeMap::new();
+    for (term, _, _) in tokens(text) {
+        *counts.entry(term).or_insert(0) += 1;
+    }
+    counts
+}
+
+// BM25 score of every matching document, with the fields that matched
+fn score_matches(
+    terms: &BTreeMap<Term, u32>,
+    mode: SearchMode,
+) -> Vec<(u64, f64, Vec<SearchField>)> {
+    let stats = INDEX_STATS.with(|cell| *cell.borrow().get());
+    let documents = stats.documents.max(1) as f64;
+    let avg_title = (stats.title_terms as f64 / documents).max(1.0);
+    let avg_description = (stats.description_terms as f64 / documents).max(1.0);
+
+    // Postings of each query term, grouped by document
+    let mut matches: BTreeMap<u64, Vec<(f64, Posting)>> = BTreeMap::new();
+    INDEX.with(|index| {
+        let index = index.borrow();
+        for term in terms.keys() {
+            let postings: Vec<(u64, Posting)> = index
+                .range((term.clone(), 0)..=(term.clone(), u64::MAX))
+                .map(|((_, id), posting)| (id, posting))
+                .collect();
+            let df = postings.len() as f64;
+            let idf = (1.0 + (documents - df + 0.5) / (df + 0.5)).ln();
+            for (id, posting) in postings {
+                matches.entry(id).or_default().push((idf, posting));
+            }
+        }
+    });
+
+    FIELD_LENGTHS.with(|lengths| {
+        let lengths = lengths.borrow();
+        matches
+            .into_iter()
+            .filter(|(_, postings)| mode == SearchMode::Any || postings.len() == terms.len())
+            .map(|(id, postings)| {
+                let field_lengths = lengths.get(&id).unwrap_or_default();
+                let mut score = 0.0;
+                let mut in_title = false;
+                let mut in_description = false;
+                for (idf, posting) in postings {
+                    in_title |= posting.title_tf > 0;
+                    in_description |= posting.description_tf > 0;
+                    score += idf
+                        * (TITLE_WEIGHT
+                            * bm25_tf(posting.title_tf, field_lengths.title, avg_title)
+                            + bm25_tf(
+                                posting.description_tf,
+                                field_lengths.description,
+                                avg_description,
+                            ));
+                }
+
+                let mut matched_fields = Vec::new();
+                if in_title {
+                    matched_fields.push(SearchField::Title);
+                }
+                if in_description {
+                    matched_fields.push(SearchField::Description);
+                }
+                (id, score, matched_fields)
+            })
+            .collect()
+    })
+}
+
+// Saturated, length-normalized term frequency of one field
+fn bm25_tf(tf: u32, length: u32, avg_length: f64) -> f64 {
+    let tf = tf as f64;
+    let norm = 1.0 - BM25_B + BM25_B * length as f64 / avg_length;
+    tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * norm)
+}
+
+// Excerpt of the field around its first matched term, with every match inside highlighted.
+// Titles are returned whole; descriptions are cut to about SNIPPET_CHARS characters.
+fn snippet(document: &Document, field: SearchField, terms: &BTreeMap<Term, u32>) -> Option<Snippet> {
+    let text = match field {
+        SearchField::Title => &document.title,
+        SearchField::Description => &document.description,
+    };
+    let matches: Vec<(usize, usize)> = tokens(text)
+        .into_iter()
+        .filter(|(term, _, _)| terms.contains_key(term))
+        .map(|(_, start, end)| (start, end))
+        .collect();
+    let (first_start, _) = *matches.first()?;
+
+    let total = text.chars().count();
+    let (window_start, window_end) = match field {
+        SearchField::Title => (0, total),
+        SearchField::Description => {
+            let start = first_start.saturating_sub(SNIPPET_CHARS / 4);
+            (start, (start + SNIPPET_CHARS).min(total))
+        }
+    };
+
+    let highlights = matches
+        .into_iter()
+        .filter(|(start, end)| *start >= window_start && *end <= window_end)
+        .map(|(start, end)| Highlight {
+            start: (start - window_start) as u32,
+            end: (end - window_start) as u32,
+        })
+        .collect();
+    Some(Snippet {
+        field,
+        text: text.chars().skip(window_start).take(window_end - window_start).collect(),
+        highlights,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_document;
+
+    fn highlights(snippet: &Snippet) -> Vec<(u32, u32)> {
+        snippet.highlights.iter().map(|highlight| (highlight.start, highlight.end)).collect()
+    }
+
+    #[test]
+    fn splits_text_into_lowercase_tokens() {
+        let found: Vec<(String, usize, usize)> = tokens("Hello, wörld! Ünïcode-42")
+            .into_iter()
+            .map(|(term, start, end)| (term.0, start, end))
+            .collect();
+        let expected = [("hello", 0, 5), ("wörld", 7, 12), ("ünïcode", 14, 21), ("42", 22, 24)]
+            .map(|(term, start, end)| (term.to_string(), start, end));
+        assert_eq!(found, expected);
+        assert!(tokens("  ,. ").is_empty());
+
+        // Long tokens are cut to MAX_TERM_LEN bytes without splitting a character
+        let long = "é".repeat(MAX_TERM_LEN);
+        assert_eq!(normalize(&long).0, "é".repeat(MAX_TERM_LEN / 2));
+    }
+
+    #[test]
+    fn highlights_matches_in_snippets() {
+        let terms = term_counts("Report needle");
+        let title = test_document(1, "Annual report: Report 2024", "", 1);
+        let excerpt = snippet(&title, SearchField::Title, &terms).expect("the title matches");
+        assert_eq!(excerpt.text, "Annual report: Report 2024");
+        assert_eq!(highlights(&excerpt), [(7, 13), (15, 21)]);
+
+        // Descriptions are cut to a window starting a little before the first match
+        let description = format!("{}needle{}", "x ".repeat(100), " y".repeat(100));
+        let long = test_document(1, "", &description, 1);
+        let excerpt =
+            snippet(&long, SearchField::Description, &terms).expect("the description matches");
+        let window_start = 200 - SNIPPET_CHARS / 4;
+        assert_eq!(excerpt.text.chars().count(), SNIPPET_CHARS);
+        assert_eq!(excerpt.text, description[window_start..window_start + SNIPPET_CHARS]);
+        assert_eq!(highlights(&excerpt), [(40, 46)]);
+
+        assert!(snippet(&long, SearchField::Title, &terms).is_none());
+    }
+}
@@ -0,0 +1,260 @@
+// Signatures of principals on document versions.
+//
+// A signatory signs a digest of the document id, the version, the version's hash from
+// the history chain and their own principal with their ed25519 or secp256k1 key, so a
+// signature cannot be passed off as someone else's or moved to another version. The
+// canister verifies the signature before storing it. A signature only counts while the
+// version it covers is the document's current one; any later version invalidates it.
+use crate::audit::{self, AuditOperation};
+use crate::integrity::write_field;
+use crate::{
+    authenticate, authorize, load_document, load_version, readable_document, Error, Memory, Role,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ed25519_dalek::Verifier;
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Longest accepted public key, an uncompressed SEC1 secp256k1 key
+const MAX_PUBLIC_KEY_LEN: usize = 65;
+
+const SIGNATURE_DOMAIN: &[u8] = b"document-version-signature";
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum SignatureScheme {
+    // 32-byte public key, 64-byte signature over the signing message
+    Ed25519,
+    // SEC1 public key, 64-byte r || s signature over SHA-256 of the signing message
+    EcdsaSecp256k1,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct VersionSignature {
+    document_id: u64,
+    version: u64,
+    // Hash of the signed version from the history chain
+    version_hash: ByteBuf,
+    signer: Principal,
+    scheme: SignatureScheme,
+    public_key: ByteBuf,
+    signature: ByteBuf,
+    signed_at: u64,
+}
+
+// Storable trait for VersionSignature
+impl Storable for VersionSignature {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// BoundedStorable trait for VersionSignature
+impl BoundedStorable for VersionSignature {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Signatures keyed by (document id, sequence within the document)
+    static SIGNATURES: RefCell<StableBTreeMap<(u64, u64), VersionSignature, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+}
+
+// Sign the current version of a document with the caller's key
+#[ic_cdk::update]
+fn sign_document_version(
+    id: u64,
+    version: u64,
+    scheme: SignatureScheme,
+    public_key: ByteBuf,
+    signature: ByteBuf,
+) -> Result<VersionSignature, Error> {
+    audit::audited(AuditOperation::SignDocumentVersion, Some(id), || {
+        authenticate()?;
+        let document = load_document(id)?;
+        if document.is_deleted {
+            return Err(Error::DocumentDeleted);
+        }
+        authorize(&document, Role::Reader)?;
+        if version != document.version {
+            return Err(Error::VersionConflict { current: document.version });
+        }
+        let version_hash = load_version(id, version)?.hash;
+
+        if public_key.len() > MAX_PUBLIC_KEY_LEN {
+            return Err(Error::InvalidArgument {
+                msg: format!("Public key cannot be longer than {} bytes", MAX_PUBLIC_KEY_LEN),
+            });
+        }
+        record_signature(VersionSignature {
+            document_id: id,
+            version,
+            version_hash,
+            signer: caller(),
+            scheme,
+            public_key,
+            signature,
+            signed_at: time(),
+        })
+    })
+}
+
+// Message the caller signs for a version of a document
+#[ic_cdk::query]
+fn get_signing_message(id: u64, version: u64) -> Result<ByteBuf, Error> {
+    readable_document(id)?;
+    let version_hash = load_version(id, version)?.hash;
+    Ok(ByteBuf::from(signing_message(id, version, &version_hash, &caller()).to_vec()))
+}
+
+// Signatures on the current version of a document; signatures on earlier versions
+// are no longer valid and are left out
+#[ic_cdk::query]
+fn list_signatures(id: u64) -> Result<Vec<VersionSignature>, Error> {
+    let document = load_document(id)?;
+    authorize(&document, Role::Reader)?;
+
+    Ok(SIGNATURES.with(|signatures| {
+        signatures
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, signature)| signature)
+            .filter(|signature| {
+                signature.version == document.version
+                    && signature.version_hash == document.history_head
+            })
+            .collect()
+    }))
+}
+
+// Drop the signatures of a document that no longer exists
+pub(crate) fn remove_signatures(id: u64) {
+    SIGNATURES.with(|signatures| {
+        let mut signatures = signatures.borrow_mut();
+        let keys: Vec<(u64, u64)> =
+            signatures.range((id, 0)..=(id, u64::MAX)).map(|(key, _)| key).collect();
+        for key in keys {
+            signatures.remove(&key);
+        }
+    });
+}
+
+// Verify a signature against its signer's message and store it. A signature already
+// stored is refused whoever submits it; one made for another document cannot verify here.
+fn record_signature(entry: VersionSignature) -> Result<VersionSignature, Error> {
+    let message =
+        signing_message(entry.document_id, entry.version, &entry.version_hash, &entry.signer);
+    if !verify(entry.scheme, &entry.public_key, &message, &entry.signature) {
+        return Err(Error::InvalidArgument {
+            msg: "Signature does not match the signing message and public key".to_string(),
+        });
+    }
+
+    let id = entry.document_id;
+    SIGNATURES.with(|signatures| {
+        let mut signatures = signatures.borrow_mut();
+        let mut sequence = 0;
+        for ((_, key), existing) in signatures.range((id, 0)..=(id, u64::MAX)) {
+            if existing.version == entry.version && existing.signer == entry.signer {
+                return Err(Error::InvalidArgument {
+                    msg: format!("Version {} is already signed by {}", entry.version, entry.signer),
+                });
+            }
+            if existing.public_key == entry.public_key && existing.signature == entry.signature {
+                return Err(Error::InvalidArgument {
+                    msg: "This signature has already been submitted".to_string(),
+                });
+            }
+            sequence = key + 1;
+        }
+        signatures.insert((id, sequence), entry.clone());
+        Ok(entry)
+    })
+}
+
+// SHA-256 over the domain and the length-prefixed document id, version, version hash
+// and signer principal
+fn signing_message(
+    document_id: u64,
+    version: u64,
+    version_hash: &[u8],
+    signer: &Principal,
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    write_field(&mut hasher, SIGNATURE_DOMAIN);
+    write_field(&mut hasher, &document_id.to_be_bytes());
+    write_field(&mut hasher, &version.to_be_bytes());
+    write_field(&mut hasher, version_hash);
+    write_field(&mut hasher, signer.as_slice());
+    hasher.finalize().into()
+}
+
+fn verify(scheme: SignatureScheme, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    match scheme {
+        SignatureScheme::Ed25519 => {
+            let (Ok(public_key), Ok(signature)) = (
+                <[u8; 32]>::try_from(public_key),
+                ed25519_dalek::Signature::from_slice(signature),
+            ) else {
+                return false;
+            };
+            ed25519_dalek::VerifyingKey::from_bytes(&public_key)
+                .is_ok_and(|key| key.verify(message, &signature).is_ok())
+        }
+        SignatureScheme::EcdsaSecp256k1 => {
+            let (Ok(key), Ok(signature)) = (
+                k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key),
+                k256::ecdsa::Signature::from_slice(signature),
+            ) else {
+                return false;
+            };
+            let signature = signature.normalize_s().unwrap_or(signature);
+            key.verify(message, &signature).is_ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn rejects_replayed_signatures() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let version_hash = ByteBuf::from(vec![3; 32]);
+        let signer = Principal::from_slice(&[1; 29]);
+        let other = Principal::from_slice(&[2; 29]);
+        let message = signing_message(1, 2, &version_hash, &signer);
+        let entry = |signer: Principal| VersionSignature {
+            document_id: 1,
+            version: 2,
+            version_hash: version_hash.clone(),
+            signer,
+            scheme: SignatureScheme::Ed25519,
+            public_key: ByteBuf::from(key.verifying_key().to_bytes().to_vec()),
+            signature: ByteBuf::from(key.sign(&message).to_bytes().to_vec()),
+            signed_at: 0,
+        };
+
+        assert!(record_signature(entry(signer)).is_ok());
+        // Another reader resubmitting the listed key and signature
+        assert!(matches!(record_signature(entry(other)), Err(Error::InvalidArgument { .. })));
+        assert!(matches!(record_signature(entry(signer)), Err(Error::InvalidArgument { .. })));
+        // The same signature does not verify for another version either
+        let mut moved = entry(signer);
+        moved.version = 3;
+        assert!(record_signature(moved).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// Reads of documents as they were at a past moment.
+//
+// The state of a document at a timestamp is rebuilt from its version history (the
+// latest version whose `updated_at` is not after the timestamp) and its delete and
+// restore events. Access is checked against the document's current permissions, and
+// purged documents cannot be read back since nothing of them is kept.
+use crate::search::{self, SearchMode, SearchResult};
+use crate::{
+    authorize, has_role, load_document, load_version, schema, DeletionRecord, Document,
+    DocumentEventKind, Error, Role, EVENTS, STORAGE,
+};
+use ic_cdk::api::caller;
+
+// Maximum number of documents a single past search looks at
+const MAX_TIMELINE_SCAN: usize = 500;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TimelineSearchPage {
+    // Matches among the documents looked at by this call, ranked among themselves
+    results: Vec<SearchResult>,
+    // Id to pass as `after` to search the following documents, absent once all were searched
+    next_after: Option<u64>,
+}
+
+// Retrieve a document as it was at `timestamp`, soft-deleted or not
+#[ic_cdk::query]
+fn get_document_at(id: u64, timestamp: u64) -> Result<Document, Error> {
+    let document = load_document(id)?;
+    authorize(&document, Role::Reader)?;
+    document_at(&document, timestamp)?.ok_or_else(|| Error::NotFound {
+        msg: format!("Document with id {} did not exist at {}", id, timestamp),
+    })
+}
+
+// Search the documents the caller can read as they were at `timestamp`, looking at up to
+// MAX_TIMELINE_SCAN documents with ids after `after`. Documents that were soft-deleted
+// at that moment are left out, like `search_documents` does.
+#[ic_cdk::query]
+fn search_documents_at(
+    query: String,
+    mode: SearchMode,
+    timestamp: u64,
+    after: Option<u64>,
+) -> TimelineSearchPage {
+    let principal = caller();
+    if after == Some(u64::MAX) {
+        return TimelineSearchPage { results: Vec::new(), next_after: None };
+    }
+    let start = after.map_or(0, |id| id + 1);
+    let mut last_scanned = None;
+    let mut documents = Vec::new();
+    STORAGE.with(|service| {
+        for (id, stored) in service.borrow().range(start..).take(MAX_TIMELINE_SCAN) {
+            last_scanned = Some(id);
+            let Some((_, document)) = schema::decoded((id, stored)) else { continue };
+            if !has_role(&document, &principal, Role::Reader) {
+                continue;
+            }
+            if let Ok(Some(past)) = document_at(&document, timestamp) {
+                if !past.is_deleted {
+                    documents.push(past);
+                }
+            }
+        }
+    });
+    let next_after = last_scanned.filter(|id| {
+        STORAGE.with(|service| service.borrow().range(id + 1..).next().is_some())
+    });
+    TimelineSearchPage { results: search::search_snapshots(&query, mode, documents), next_after }
+}
+
+// State of the document at `timestamp`, or None if it had not been created yet. Fails
+// if a version up to that moment cannot be decoded.
+fn document_at(document: &Document, timestamp: u64) -> Result<Option<Document>, Error> {
+    let id = document.id;
+    if document.created_at > timestamp {
+        return Ok(None);
+    }
+    // Versions 1 to `document.version` are recorded in time order, so binary search
+    // for the latest one at or before `timestamp`
+    let (mut low, mut high) = (1, document.version);
+    let mut version = None;
+    while low <= high {
+        let middle = low + (high - low) / 2;
+        let candidate = load_version(id, middle)?;
+        if candidate.updated_at <= timestamp {
+            version = Some(candidate);
+            low = middle + 1;
+        } else {
+            high = middle - 1;
+        }
+    }
+    let Some(version) = version else { return Ok(None) };
+
+    let deletion = EVENTS.with(|events| {
+        events
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, event)| event)
+            .take_while(|event| event.timestamp <= timestamp)
+            .fold(None, |deletion, event| match event.kind {
+                DocumentEventKind::Created => deletion,
+                DocumentEventKind::Deleted => Some(DeletionRecord {
+                    deleted_by: event.actor,
+                    deleted_at: event.timestamp,
+                    reason: None,
+                }),
+                DocumentEventKind::Restored => None,
+            })
+    });
+    // Events do not carry the reason; it is only known for the current deletion
+    let deletion = deletion.map(|mut deletion| {
+        deletion.reason = document
+            .deletion
+            .as_ref()
+            .filter(|current| current.deleted_at == deletion.deleted_at)
+            .and_then(|current| current.reason.clone());
+        deletion
+    });
+
+    let updated = version.version > 1;
+    Ok(Some(Document {
+        id,
+        owner: document.owner,
+        title: version.title,
+        description: version.description,
+        file_url: version.file_url,
+        content_id: version.content_id,
+        version: version.version,
+        created_at: document.created_at,
+        updated_at: updated.then_some(version.updated_at),
+        updated_by: updated.then_some(version.updated_by),
+        is_deleted: deletion.is_some(),
+        deletion,
+        history_head: version.hash,
+    }))
+}